@@ -0,0 +1,901 @@
+use chrono::{Datelike, Local, Timelike};
+use flate2::{write::DeflateEncoder, Compression};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use zip::ZipArchive;
+
+use crate::atomic::replace_file;
+use crate::{Error, Result};
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x0605_4b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x0807_4b50;
+
+const LOCAL_HEADER_LEN: usize = 30;
+const CENTRAL_HEADER_LEN: usize = 46;
+
+const FLAG_ENCRYPTED: u16 = 1;
+const FLAG_DATA_DESCRIPTOR: u16 = 1 << 3;
+const FLAG_UTF8: u16 = 1 << 11;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+
+/// "Version made by" of new entries: Unix, ZIP specification 2.0
+const VERSION_MADE_BY_UNIX: u16 = 3 << 8 | 20;
+/// Permissions of new entries, a regular file readable by everyone
+const DEFAULT_UNIX_MODE: u32 = 0o100644;
+
+/// Extra fields that describe the old contents of an entry and must not survive a replacement:
+/// ZIP64 sizes, NTFS times and extended timestamps
+const STALE_EXTRA_FIELDS: [u16; 3] = [0x0001, 0x000a, 0x5455];
+
+/// Extra field holding the name in UTF-8, which would override a new name
+const UNICODE_PATH_EXTRA_FIELDS: [u16; 1] = [0x7075];
+
+/// A rewrite of an existing archive. Entries that are not touched are copied byte for byte,
+/// including their local header, extra fields, data descriptor and central directory record,
+/// so compression, timestamps and ordering stay exactly as they were.
+pub struct ArchiveRewrite {
+    jar: PathBuf,
+    prefix_len: u64,
+    entries: Vec<RawEntry>,
+    /// Original names of the removed entries
+    removed: Vec<String>,
+    comment: Vec<u8>,
+}
+
+/// A change the rewrite makes to an entry, compared to the original archive
+pub enum EntryChange<'a> {
+    Added { name: &'a str, contents: &'a [u8] },
+    Removed { name: &'a str },
+    /// New contents, possibly under a new name as well
+    Modified { original_name: &'a str, name: &'a str, contents: &'a [u8] },
+    Renamed { original_name: &'a str, name: &'a str },
+}
+
+struct RawEntry {
+    name: String,
+    header_start: u64,
+    record_len: u64,
+    central: Vec<u8>,
+    replacement: Option<Replacement>,
+    /// Name in the original archive, `None` for added entries
+    original_name: Option<String>,
+}
+
+struct Replacement {
+    contents: Vec<u8>,
+    compression: EntryCompression,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompressionMethod {
+    Stored,
+    Deflated,
+}
+
+/// How the contents of a new or replaced entry are compressed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EntryCompression {
+    pub method: CompressionMethod,
+    /// Deflate level from 0 (fastest) to 9 (smallest)
+    pub level: u32,
+}
+
+impl Default for EntryCompression {
+    fn default() -> Self {
+        EntryCompression { method: CompressionMethod::Deflated, level: Compression::default().level() }
+    }
+}
+
+/// Whether an entry is part of the JAR signature: the manifest holding the digests, or the
+/// signature files and signature blocks next to it
+pub fn is_signature_file(name: &str) -> bool {
+    let upper = name.to_ascii_uppercase();
+    match upper.strip_prefix("META-INF/") {
+        Some(file_name) if !file_name.contains('/') => {
+            file_name == "MANIFEST.MF"
+                || file_name.starts_with("SIG-")
+                || [".SF", ".RSA", ".DSA", ".EC"].iter().any(|extension| file_name.ends_with(extension))
+        }
+        _ => false,
+    }
+}
+
+pub fn open_archive<P: AsRef<Path>>(jar: P) -> Result<ZipArchive<File>> {
+    let jar = jar.as_ref();
+    let jar_file = File::open(jar).map_err(|err| match err.kind() {
+        io::ErrorKind::NotFound => Error::JarNotFound(jar.to_path_buf()),
+        _ => Error::io(format!("Unable to open JAR file {:?}", jar), err),
+    })?;
+    ZipArchive::new(jar_file).map_err(|err| Error::zip(jar, err))
+}
+
+impl ArchiveRewrite {
+    pub fn open<P: AsRef<Path>>(jar: P) -> Result<Self> {
+        let jar = jar.as_ref();
+        let mut archive = open_archive(jar)?;
+        let prefix_len = archive.offset();
+        let comment = archive.comment().to_vec();
+
+        let mut reader = File::open(jar)?;
+        let mut entries = Vec::with_capacity(archive.len());
+        for index in 0..archive.len() {
+            let file = archive.by_index_raw(index).map_err(|err| Error::zip(jar, err))?;
+            let (name, header_start, compressed_size, central_header_start) = (
+                file.name().to_owned(),
+                file.header_start(),
+                file.compressed_size(),
+                file.central_header_start(),
+            );
+            drop(file);
+
+            let central = read_central_header(&mut reader, jar, central_header_start)?;
+            let record_len = local_record_len(&mut reader, jar, header_start, compressed_size)?;
+            entries.push(RawEntry {
+                original_name: Some(name.clone()),
+                name,
+                header_start,
+                record_len,
+                central,
+                replacement: None,
+            });
+        }
+
+        Ok(ArchiveRewrite {
+            jar: jar.to_path_buf(),
+            prefix_len,
+            entries,
+            removed: Vec::new(),
+            comment,
+        })
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.entries.iter().any(|entry| entry.name == name)
+    }
+
+    /// Whether the archive has been signed with `jarsigner`
+    pub fn is_signed(&self) -> bool {
+        self.entries.iter().any(|entry| is_signature_file(&entry.name) && entry.name.to_ascii_uppercase().ends_with(".SF"))
+    }
+
+    /// Replace the contents of an existing entry, keeping its position in the archive. Without
+    /// a compression, the entry keeps its compression method.
+    pub fn replace(&mut self, name: &str, contents: Vec<u8>, compression: Option<EntryCompression>) -> Result<()> {
+        let entry = self.entries.iter_mut()
+            .find(|entry| entry.name == name)
+            .ok_or_else(|| Error::EntryNotFound { jar: self.jar.clone(), name: name.to_string() })?;
+
+        if read_u16(&entry.central, 8) & FLAG_ENCRYPTED != 0 {
+            return Err(Error::Unsupported(format!("Unable to replace encrypted entry {:?}", name)));
+        }
+
+        let compression = compression.unwrap_or_else(|| match read_u16(&entry.central, 10) {
+            METHOD_STORED => EntryCompression { method: CompressionMethod::Stored, ..EntryCompression::default() },
+            _ => EntryCompression::default(),
+        });
+        entry.replacement = Some(Replacement { contents, compression });
+        Ok(())
+    }
+
+    /// Add a new entry at the end of the archive
+    pub fn add(&mut self, name: &str, contents: Vec<u8>, compression: EntryCompression) -> Result<()> {
+        if self.contains(name) {
+            return Err(Error::EntryExists { jar: self.jar.clone(), name: name.to_string() });
+        }
+        entry_name_len(name)?;
+
+        self.entries.push(RawEntry {
+            name: name.to_string(),
+            header_start: 0,
+            record_len: 0,
+            central: new_central_header(name),
+            replacement: Some(Replacement { contents, compression }),
+            original_name: None,
+        });
+        Ok(())
+    }
+
+    /// Rename an entry, or move every entry below it when it names a directory. A file renamed
+    /// to a name ending in `/` is moved into that directory. The data of the entries is copied
+    /// as is. Returns the old and new name of every renamed entry.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<Vec<(String, String)>> {
+        let renames = match self.contains(from) && !from.ends_with('/') {
+            true if to.ends_with('/') => {
+                let file_name = from.rsplit('/').next().unwrap_or(from);
+                vec![(from.to_string(), format!("{}{}", to, file_name))]
+            }
+            true => vec![(from.to_string(), to.to_string())],
+            false => {
+                let from_directory = format!("{}/", from.trim_end_matches('/'));
+                let to_directory = format!("{}/", to.trim_end_matches('/'));
+                if to_directory.starts_with(&from_directory) {
+                    return Err(Error::InvalidPath { name: to.to_string(), reason: format!("{:?} cannot be moved into itself", from) });
+                }
+
+                self.entries.iter()
+                    .filter_map(|entry| entry.name.strip_prefix(&from_directory)
+                        .map(|rest| (entry.name.clone(), format!("{}{}", to_directory, rest))))
+                    .collect()
+            }
+        };
+        if renames.is_empty() {
+            return Err(Error::EntryNotFound { jar: self.jar.clone(), name: from.to_string() });
+        }
+
+        let conflict = renames.iter()
+            .find(|(_, new_name)| self.contains(new_name) && !renames.iter().any(|(old_name, _)| old_name == new_name));
+        if let Some((_, new_name)) = conflict {
+            return Err(Error::EntryExists { jar: self.jar.clone(), name: new_name.clone() });
+        }
+
+        for (old_name, new_name) in &renames {
+            let entry = self.entries.iter_mut()
+                .find(|entry| &entry.name == old_name)
+                .expect("renamed entries were found above");
+            entry.central = rename_central_header(&entry.central, new_name)?;
+            entry.name = new_name.clone();
+        }
+        Ok(renames)
+    }
+
+    /// Remove an entry, or every entry below it when it names a directory.
+    /// Returns the names of the removed entries.
+    pub fn remove(&mut self, name: &str) -> Result<Vec<String>> {
+        let directory = format!("{}/", name.trim_end_matches('/'));
+        let is_removed = |entry: &RawEntry| entry.name == name || entry.name.starts_with(&directory);
+
+        let removed = self.entries.iter()
+            .filter(|entry| is_removed(entry))
+            .map(|entry| entry.name.clone())
+            .collect::<Vec<String>>();
+        if removed.is_empty() {
+            return Err(Error::EntryNotFound { jar: self.jar.clone(), name: name.to_string() });
+        }
+
+        self.removed.extend(self.entries.iter()
+            .filter(|entry| is_removed(entry))
+            .filter_map(|entry| entry.original_name.clone()));
+        self.entries.retain(|entry| !is_removed(entry));
+        Ok(removed)
+    }
+
+    /// Every change made so far, in archive order with removed entries last
+    pub fn changes(&self) -> Vec<EntryChange<'_>> {
+        let mut changes = self.entries.iter()
+            .filter_map(|entry| match (&entry.original_name, &entry.replacement) {
+                (None, Some(replacement)) => Some(EntryChange::Added { name: &entry.name, contents: &replacement.contents }),
+                (Some(original_name), Some(replacement)) => Some(EntryChange::Modified {
+                    original_name,
+                    name: &entry.name,
+                    contents: &replacement.contents,
+                }),
+                (Some(original_name), None) if entry.is_renamed() => Some(EntryChange::Renamed { original_name, name: &entry.name }),
+                _ => None,
+            })
+            .collect::<Vec<EntryChange>>();
+        changes.extend(self.removed.iter().map(|name| EntryChange::Removed { name }));
+        changes
+    }
+
+    /// Write the new archive next to the original and move it into place
+    pub fn commit(self) -> Result<()> {
+        replace_file(&self.jar, |output| self.write_to(output))
+    }
+
+    fn write_to(&self, output: &mut File) -> Result<()> {
+        let mut source = File::open(&self.jar)?;
+        let mut writer = CountingWriter::new(io::BufWriter::new(output));
+
+        copy_range(&mut source, &self.jar, &mut writer, 0, self.prefix_len)?;
+
+        let mut central_directory = Vec::new();
+        for entry in &self.entries {
+            let header_start = writer.position;
+            let mut central = match &entry.replacement {
+                Some(replacement) => write_replaced_entry(&mut writer, entry, replacement)?,
+                None if entry.is_renamed() => {
+                    write_renamed_entry(&mut source, &self.jar, &mut writer, entry)?;
+                    entry.central.clone()
+                }
+                None => {
+                    copy_range(&mut source, &self.jar, &mut writer, entry.header_start, entry.record_len)?;
+                    entry.central.clone()
+                }
+            };
+
+            central[42..46].copy_from_slice(&to_u32(header_start, "entry offset")?.to_le_bytes());
+            central_directory.extend_from_slice(&central);
+        }
+
+        let central_start = writer.position;
+        writer.write_all(&central_directory)?;
+
+        let entry_count = u16::try_from(self.entries.len())
+            .map_err(|_| Error::Unsupported(format!("Archives with more than {} entries are not supported", u16::MAX)))?;
+        let comment_len = u16::try_from(self.comment.len())
+            .map_err(|_| Error::Unsupported("Archive comment is too long".to_string()))?;
+
+        writer.write_all(&END_OF_CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes())?;
+        writer.write_all(&0u16.to_le_bytes())?;
+        writer.write_all(&0u16.to_le_bytes())?;
+        writer.write_all(&entry_count.to_le_bytes())?;
+        writer.write_all(&entry_count.to_le_bytes())?;
+        writer.write_all(&to_u32(central_directory.len() as u64, "central directory size")?.to_le_bytes())?;
+        writer.write_all(&to_u32(central_start, "central directory offset")?.to_le_bytes())?;
+        writer.write_all(&comment_len.to_le_bytes())?;
+        writer.write_all(&self.comment)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl RawEntry {
+    fn is_renamed(&self) -> bool {
+        self.original_name.as_ref().is_some_and(|original_name| *original_name != self.name)
+    }
+}
+
+fn write_replaced_entry<W: Write>(writer: &mut W, entry: &RawEntry, replacement: &Replacement) -> Result<Vec<u8>> {
+    let contents = &replacement.contents;
+    let original = &entry.central;
+    let name_len = read_u16(original, 28) as usize;
+    let extra_len = read_u16(original, 30) as usize;
+    let comment_len = read_u16(original, 32) as usize;
+    let name = &original[CENTRAL_HEADER_LEN..CENTRAL_HEADER_LEN + name_len];
+    let extra = strip_extra_fields(&original[CENTRAL_HEADER_LEN + name_len..CENTRAL_HEADER_LEN + name_len + extra_len], &STALE_EXTRA_FIELDS);
+    let comment = &original[CENTRAL_HEADER_LEN + name_len + extra_len..CENTRAL_HEADER_LEN + name_len + extra_len + comment_len];
+
+    let (method, data) = match replacement.compression.method {
+        CompressionMethod::Stored => (METHOD_STORED, contents.to_vec()),
+        CompressionMethod::Deflated => {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::new(replacement.compression.level));
+            encoder.write_all(contents)?;
+            (METHOD_DEFLATED, encoder.finish()?)
+        }
+    };
+
+    let version_needed: u16 = if method == METHOD_DEFLATED { 20 } else { 10 };
+    let flags = read_u16(original, 8) & FLAG_UTF8;
+    let (time, date) = dos_timestamp_now();
+    let crc = crc32fast::hash(contents);
+    let compressed_size = to_u32(data.len() as u64, "compressed size")?;
+    let uncompressed_size = to_u32(contents.len() as u64, "uncompressed size")?;
+    let extra_len = extra.len() as u16;
+
+    let mut local = Vec::with_capacity(LOCAL_HEADER_LEN + name.len() + extra.len());
+    local.extend_from_slice(&LOCAL_HEADER_SIGNATURE.to_le_bytes());
+    local.extend_from_slice(&version_needed.to_le_bytes());
+    local.extend_from_slice(&flags.to_le_bytes());
+    local.extend_from_slice(&method.to_le_bytes());
+    local.extend_from_slice(&time.to_le_bytes());
+    local.extend_from_slice(&date.to_le_bytes());
+    local.extend_from_slice(&crc.to_le_bytes());
+    local.extend_from_slice(&compressed_size.to_le_bytes());
+    local.extend_from_slice(&uncompressed_size.to_le_bytes());
+    local.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    local.extend_from_slice(&extra_len.to_le_bytes());
+    local.extend_from_slice(name);
+    local.extend_from_slice(&extra);
+    writer.write_all(&local)?;
+    writer.write_all(&data)?;
+
+    let mut central = Vec::with_capacity(original.len());
+    central.extend_from_slice(&CENTRAL_HEADER_SIGNATURE.to_le_bytes());
+    central.extend_from_slice(&original[4..6]);
+    central.extend_from_slice(&version_needed.to_le_bytes());
+    central.extend_from_slice(&flags.to_le_bytes());
+    central.extend_from_slice(&method.to_le_bytes());
+    central.extend_from_slice(&time.to_le_bytes());
+    central.extend_from_slice(&date.to_le_bytes());
+    central.extend_from_slice(&crc.to_le_bytes());
+    central.extend_from_slice(&compressed_size.to_le_bytes());
+    central.extend_from_slice(&uncompressed_size.to_le_bytes());
+    central.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    central.extend_from_slice(&extra_len.to_le_bytes());
+    central.extend_from_slice(&(comment.len() as u16).to_le_bytes());
+    central.extend_from_slice(&0u16.to_le_bytes());
+    central.extend_from_slice(&original[36..42]);
+    central.extend_from_slice(&0u32.to_le_bytes());
+    central.extend_from_slice(name);
+    central.extend_from_slice(&extra);
+    central.extend_from_slice(comment);
+    Ok(central)
+}
+
+/// Copy an entry under its new name. Only the name in the local header changes, the data and
+/// data descriptor are copied byte for byte.
+fn write_renamed_entry<W: Write>(source: &mut File, jar: &Path, writer: &mut W, entry: &RawEntry) -> Result<()> {
+    let mut local = vec![0; LOCAL_HEADER_LEN];
+    source.seek(SeekFrom::Start(entry.header_start))?;
+    source.read_exact(&mut local)?;
+    let old_name_len = read_u16(&local, 26) as u64;
+    let extra_len = read_u16(&local, 28) as u64;
+    let mut extra = vec![0; extra_len as usize];
+    source.seek(SeekFrom::Current(old_name_len as i64))?;
+    source.read_exact(&mut extra)?;
+    let extra = strip_extra_fields(&extra, &UNICODE_PATH_EXTRA_FIELDS);
+
+    // The UTF-8 flag must agree with the central directory record
+    let flags = (read_u16(&local, 6) & !FLAG_UTF8) | (read_u16(&entry.central, 8) & FLAG_UTF8);
+    local[6..8].copy_from_slice(&flags.to_le_bytes());
+    local[26..28].copy_from_slice(&(entry.name.len() as u16).to_le_bytes());
+    local[28..30].copy_from_slice(&(extra.len() as u16).to_le_bytes());
+    writer.write_all(&local)?;
+    writer.write_all(entry.name.as_bytes())?;
+    writer.write_all(&extra)?;
+
+    let data_start = entry.header_start + LOCAL_HEADER_LEN as u64 + old_name_len + extra_len;
+    copy_range(source, jar, writer, data_start, entry.record_len - (data_start - entry.header_start))
+}
+
+/// Copy of a central directory record with a different name
+fn rename_central_header(central: &[u8], name: &str) -> Result<Vec<u8>> {
+    let name_len = entry_name_len(name)?;
+    let old_name_len = read_u16(central, 28) as usize;
+    let extra_len = read_u16(central, 30) as usize;
+    let extra = strip_extra_fields(&central[CENTRAL_HEADER_LEN + old_name_len..CENTRAL_HEADER_LEN + old_name_len + extra_len], &UNICODE_PATH_EXTRA_FIELDS);
+    let flags = match name.is_ascii() {
+        true => read_u16(central, 8),
+        false => read_u16(central, 8) | FLAG_UTF8,
+    };
+
+    let mut renamed = Vec::with_capacity(central.len() - old_name_len + name.len());
+    renamed.extend_from_slice(&central[..CENTRAL_HEADER_LEN]);
+    renamed[8..10].copy_from_slice(&flags.to_le_bytes());
+    renamed[28..30].copy_from_slice(&name_len.to_le_bytes());
+    renamed[30..32].copy_from_slice(&(extra.len() as u16).to_le_bytes());
+    renamed.extend_from_slice(name.as_bytes());
+    renamed.extend_from_slice(&extra);
+    renamed.extend_from_slice(&central[CENTRAL_HEADER_LEN + old_name_len + extra_len..]);
+    Ok(renamed)
+}
+
+/// Central directory record of a new entry. Sizes, checksum and offset are filled in when the
+/// entry is written.
+fn new_central_header(name: &str) -> Vec<u8> {
+    let flags = if name.is_ascii() { 0 } else { FLAG_UTF8 };
+
+    let mut central = Vec::with_capacity(CENTRAL_HEADER_LEN + name.len());
+    central.extend_from_slice(&CENTRAL_HEADER_SIGNATURE.to_le_bytes());
+    central.extend_from_slice(&VERSION_MADE_BY_UNIX.to_le_bytes());
+    central.extend_from_slice(&20u16.to_le_bytes());
+    central.extend_from_slice(&flags.to_le_bytes());
+    central.extend_from_slice(&[0; 18]);
+    central.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    central.extend_from_slice(&[0; 8]);
+    central.extend_from_slice(&(DEFAULT_UNIX_MODE << 16).to_le_bytes());
+    central.extend_from_slice(&0u32.to_le_bytes());
+    central.extend_from_slice(name.as_bytes());
+    central
+}
+
+fn read_central_header<R: Read + Seek>(reader: &mut R, jar: &Path, offset: u64) -> Result<Vec<u8>> {
+    let mut header = vec![0; CENTRAL_HEADER_LEN];
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(&mut header)?;
+    if read_u32(&header, 0) != CENTRAL_HEADER_SIGNATURE {
+        return Err(corrupt(jar, format!("invalid central directory header at offset {}", offset)));
+    }
+
+    let variable_len = read_u16(&header, 28) as usize
+        + read_u16(&header, 30) as usize
+        + read_u16(&header, 32) as usize;
+    header.resize(CENTRAL_HEADER_LEN + variable_len, 0);
+    reader.read_exact(&mut header[CENTRAL_HEADER_LEN..])?;
+    Ok(header)
+}
+
+/// Length of the local header, entry data and trailing data descriptor of an entry
+fn local_record_len<R: Read + Seek>(reader: &mut R, jar: &Path, header_start: u64, compressed_size: u64) -> Result<u64> {
+    let mut header = [0; LOCAL_HEADER_LEN];
+    reader.seek(SeekFrom::Start(header_start))?;
+    reader.read_exact(&mut header)?;
+    if read_u32(&header, 0) != LOCAL_HEADER_SIGNATURE {
+        return Err(corrupt(jar, format!("invalid local file header at offset {}", header_start)));
+    }
+
+    let header_len = (LOCAL_HEADER_LEN + read_u16(&header, 26) as usize + read_u16(&header, 28) as usize) as u64;
+    let mut record_len = header_len + compressed_size;
+
+    if read_u16(&header, 6) & FLAG_DATA_DESCRIPTOR != 0 {
+        let mut signature = [0; 4];
+        reader.seek(SeekFrom::Start(header_start + record_len))?;
+        reader.read_exact(&mut signature)?;
+        let zip64 = compressed_size >= u32::MAX as u64 || read_u32(&header, 22) == u32::MAX;
+        let descriptor_len = if zip64 { 20 } else { 12 };
+        record_len += descriptor_len;
+        if u32::from_le_bytes(signature) == DATA_DESCRIPTOR_SIGNATURE {
+            record_len += 4;
+        }
+    }
+
+    Ok(record_len)
+}
+
+fn copy_range<W: Write>(source: &mut File, jar: &Path, writer: &mut W, start: u64, len: u64) -> Result<()> {
+    source.seek(SeekFrom::Start(start))?;
+    let copied = io::copy(&mut source.take(len), writer)?;
+    if copied != len {
+        return Err(corrupt(jar, format!("unexpected end of archive at offset {}", start + copied)));
+    }
+    Ok(())
+}
+
+fn strip_extra_fields(extra: &[u8], stripped_fields: &[u16]) -> Vec<u8> {
+    let mut kept = Vec::with_capacity(extra.len());
+    let mut offset = 0;
+    while offset + 4 <= extra.len() {
+        let kind = read_u16(extra, offset);
+        let end = (offset + 4 + read_u16(extra, offset + 2) as usize).min(extra.len());
+        if !stripped_fields.contains(&kind) {
+            kept.extend_from_slice(&extra[offset..end]);
+        }
+        offset = end;
+    }
+    kept
+}
+
+fn dos_timestamp_now() -> (u16, u16) {
+    let now = Local::now();
+    let time = (now.hour() << 11 | now.minute() << 5 | (now.second() / 2)) as u16;
+    let date = ((now.year().max(1980) - 1980) as u32) << 9 | now.month() << 5 | now.day();
+    (time, date as u16)
+}
+
+fn entry_name_len(name: &str) -> Result<u16> {
+    u16::try_from(name.len())
+        .map_err(|_| Error::InvalidPath { name: name.to_string(), reason: format!("entry names are at most {} bytes long", u16::MAX) })
+}
+
+fn to_u32(value: u64, what: &str) -> Result<u32> {
+    u32::try_from(value).map_err(|_| Error::Unsupported(format!("ZIP64 archives are not supported ({} is {})", what, value)))
+}
+
+fn corrupt(jar: &Path, reason: String) -> Error {
+    Error::CorruptArchive { jar: jar.to_path_buf(), reason }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+struct CountingWriter<W: Write> {
+    inner: W,
+    position: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    fn new(inner: W) -> Self {
+        CountingWriter { inner, position: 0 }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    /// Extra field of the manifest, which has to survive every rewrite
+    const CUSTOM_EXTRA_FIELD: [u8; 8] = [0xfe, 0xca, 4, 0, b'j', b'a', b'r', b'!'];
+
+    /// What a reader sees of an entry, which an untouched entry must keep
+    #[derive(Debug, PartialEq)]
+    struct EntrySnapshot {
+        name: String,
+        compression: zip::CompressionMethod,
+        crc32: u32,
+        modified: (u16, u16),
+        extra: Vec<u8>,
+        contents: Vec<u8>,
+    }
+
+    /// An archive holding a streamed entry with a data descriptor, a deflated entry with an extra
+    /// field, a stored entry and a directory
+    fn build_archive(directory: &TempDir) -> PathBuf {
+        let jar = directory.path().join("app.jar");
+        let timestamp = zip::DateTime::from_date_and_time(2020, 1, 2, 3, 4, 6).unwrap();
+        let deflated = FileOptions::default().last_modified_time(timestamp);
+        let stored = deflated.compression_method(zip::CompressionMethod::Stored);
+
+        let mut writer = ZipWriter::new(io::Cursor::new(Vec::new()));
+        writer.start_file("streamed.txt", deflated).unwrap();
+        writer.write_all(&b"written by a streaming tool\n".repeat(20)).unwrap();
+        writer.start_file_with_extra_data("META-INF/MANIFEST.MF", deflated).unwrap();
+        writer.write_all(&CUSTOM_EXTRA_FIELD).unwrap();
+        writer.end_extra_data().unwrap();
+        writer.write_all(b"Manifest-Version: 1.0\n").unwrap();
+        writer.start_file("lib/native.so", stored).unwrap();
+        writer.write_all(&[0x7f, b'E', b'L', b'F', 0, 1, 2, 3]).unwrap();
+        writer.add_directory("config/", deflated).unwrap();
+        writer.start_file("config/app.properties", deflated).unwrap();
+        writer.write_all(b"timeout=30\n").unwrap();
+        writer.start_file("config/old.txt", stored).unwrap();
+        writer.write_all(b"old name\n").unwrap();
+        writer.start_file("remove.me", deflated).unwrap();
+        writer.write_all(b"going away\n").unwrap();
+        let mut archive = writer.finish().unwrap().into_inner();
+
+        stream_first_entry(&mut archive);
+        fs::write(&jar, archive).unwrap();
+        jar
+    }
+
+    /// Turn the first entry written by `ZipWriter` into one streamed with a data descriptor, as
+    /// `jar` and most build tools write them: no checksum and sizes in the local header, followed
+    /// by the data descriptor
+    fn stream_first_entry(archive: &mut Vec<u8>) {
+        let data_end = LOCAL_HEADER_LEN + read_u16(archive, 26) as usize + read_u16(archive, 28) as usize
+            + read_u32(archive, 18) as usize;
+        let mut descriptor = DATA_DESCRIPTOR_SIGNATURE.to_le_bytes().to_vec();
+        descriptor.extend_from_slice(&archive[14..26]);
+
+        let flags = read_u16(archive, 6) | FLAG_DATA_DESCRIPTOR;
+        archive[6..8].copy_from_slice(&flags.to_le_bytes());
+        archive[14..26].fill(0);
+        archive.splice(data_end..data_end, descriptor.iter().copied());
+
+        // Everything after the entry moved by the length of the descriptor
+        let end_of_central_directory = archive.len() - 22;
+        let central_start = read_u32(archive, end_of_central_directory + 16) + descriptor.len() as u32;
+        archive[end_of_central_directory + 16..end_of_central_directory + 20].copy_from_slice(&central_start.to_le_bytes());
+        let mut offset = central_start as usize;
+        while read_u32(archive, offset) == CENTRAL_HEADER_SIGNATURE {
+            match read_u32(archive, offset + 42) {
+                0 => archive[offset + 8..offset + 10].copy_from_slice(&flags.to_le_bytes()),
+                header_start => archive[offset + 42..offset + 46]
+                    .copy_from_slice(&(header_start + descriptor.len() as u32).to_le_bytes()),
+            }
+            offset += CENTRAL_HEADER_LEN + read_u16(archive, offset + 28) as usize
+                + read_u16(archive, offset + 30) as usize + read_u16(archive, offset + 32) as usize;
+        }
+    }
+
+    fn snapshot(jar: &Path) -> Vec<EntrySnapshot> {
+        let mut archive = open_archive(jar).unwrap();
+        (0..archive.len())
+            .map(|index| {
+                let mut file = archive.by_index(index).unwrap();
+                let mut contents = Vec::new();
+                // Reading to the end checks the CRC
+                file.read_to_end(&mut contents).unwrap();
+                EntrySnapshot {
+                    name: file.name().to_string(),
+                    compression: file.compression(),
+                    crc32: file.crc32(),
+                    modified: (file.last_modified().datepart(), file.last_modified().timepart()),
+                    extra: file.extra_data().to_vec(),
+                    contents,
+                }
+            })
+            .collect()
+    }
+
+    /// Local header, data and data descriptor of an entry as they are stored, measured with the
+    /// zip crate rather than the code under test
+    fn raw_record(jar: &Path, name: &str) -> Vec<u8> {
+        let mut archive = open_archive(jar).unwrap();
+        let file = archive.by_name(name).unwrap();
+        let data_end = (file.data_start() + file.compressed_size()) as usize;
+        let header_start = file.header_start() as usize;
+        drop(file);
+
+        let bytes = fs::read(jar).unwrap();
+        let descriptor_len = match read_u16(&bytes, header_start + 6) & FLAG_DATA_DESCRIPTOR {
+            0 => 0,
+            _ => 16,
+        };
+        bytes[header_start..data_end + descriptor_len].to_vec()
+    }
+
+    /// Every entry not named in `touched` is still there, in the same order and unchanged
+    fn assert_untouched(before: &[EntrySnapshot], after: &[EntrySnapshot], touched: &[&str]) {
+        let untouched = |snapshots: &[EntrySnapshot]| snapshots.iter()
+            .filter(|snapshot| !touched.contains(&snapshot.name.as_str()))
+            .map(|snapshot| format!("{:?}", snapshot))
+            .collect::<Vec<String>>();
+        assert_eq!(untouched(before), untouched(after));
+    }
+
+    fn find<'a>(snapshots: &'a [EntrySnapshot], name: &str) -> &'a EntrySnapshot {
+        snapshots.iter().find(|snapshot| snapshot.name == name).unwrap()
+    }
+
+    fn names(snapshots: &[EntrySnapshot]) -> Vec<&str> {
+        snapshots.iter().map(|snapshot| snapshot.name.as_str()).collect()
+    }
+
+    #[test]
+    fn untouched_entries_are_copied_byte_for_byte() {
+        let directory = TempDir::new().unwrap();
+        let jar = build_archive(&directory);
+        let streamed_record = raw_record(&jar, "streamed.txt");
+        assert_eq!(read_u16(&streamed_record, 6) & FLAG_DATA_DESCRIPTOR, FLAG_DATA_DESCRIPTOR);
+        let streamed = find(&snapshot(&jar), "streamed.txt").crc32;
+        let descriptor = &streamed_record[streamed_record.len() - 16..];
+        assert_eq!((read_u32(descriptor, 0), read_u32(descriptor, 4)), (DATA_DESCRIPTOR_SIGNATURE, streamed));
+        let stored_record = raw_record(&jar, "lib/native.so");
+
+        let mut rewrite = ArchiveRewrite::open(&jar).unwrap();
+        rewrite.replace("config/app.properties", b"timeout=60\n".to_vec(), None).unwrap();
+        rewrite.commit().unwrap();
+
+        assert_eq!(raw_record(&jar, "streamed.txt"), streamed_record);
+        assert_eq!(raw_record(&jar, "lib/native.so"), stored_record);
+    }
+
+    #[test]
+    fn replace_keeps_position_and_compression() {
+        let directory = TempDir::new().unwrap();
+        let jar = build_archive(&directory);
+        let before = snapshot(&jar);
+
+        let mut rewrite = ArchiveRewrite::open(&jar).unwrap();
+        rewrite.replace("config/old.txt", b"new contents\n".to_vec(), None).unwrap();
+        rewrite.replace("META-INF/MANIFEST.MF", b"Manifest-Version: 2.0\n".to_vec(), None).unwrap();
+        rewrite.commit().unwrap();
+
+        let after = snapshot(&jar);
+        assert_untouched(&before, &after, &["config/old.txt", "META-INF/MANIFEST.MF"]);
+        assert_eq!(names(&before), names(&after));
+
+        let replaced = find(&after, "config/old.txt");
+        assert_eq!(replaced.contents, b"new contents\n");
+        assert_eq!(replaced.compression, zip::CompressionMethod::Stored);
+        assert_eq!(replaced.crc32, crc32fast::hash(b"new contents\n"));
+        let manifest = find(&after, "META-INF/MANIFEST.MF");
+        assert_eq!(manifest.contents, b"Manifest-Version: 2.0\n");
+        assert_eq!(manifest.compression, zip::CompressionMethod::Deflated);
+        assert_eq!(manifest.extra, CUSTOM_EXTRA_FIELD);
+    }
+
+    #[test]
+    fn replace_streamed_entry() {
+        let directory = TempDir::new().unwrap();
+        let jar = build_archive(&directory);
+        let before = snapshot(&jar);
+
+        let mut rewrite = ArchiveRewrite::open(&jar).unwrap();
+        let compression = EntryCompression { method: CompressionMethod::Stored, level: 0 };
+        rewrite.replace("streamed.txt", b"rewritten\n".to_vec(), Some(compression)).unwrap();
+        rewrite.commit().unwrap();
+
+        let after = snapshot(&jar);
+        assert_untouched(&before, &after, &["streamed.txt"]);
+        let replaced = find(&after, "streamed.txt");
+        assert_eq!(replaced.contents, b"rewritten\n");
+        assert_eq!(replaced.compression, zip::CompressionMethod::Stored);
+        assert_eq!(read_u16(&raw_record(&jar, "streamed.txt"), 6) & FLAG_DATA_DESCRIPTOR, 0);
+    }
+
+    #[test]
+    fn remove_entry_and_directory() {
+        let directory = TempDir::new().unwrap();
+        let jar = build_archive(&directory);
+        let before = snapshot(&jar);
+
+        let mut rewrite = ArchiveRewrite::open(&jar).unwrap();
+        assert_eq!(rewrite.remove("remove.me").unwrap(), ["remove.me"]);
+        assert_eq!(rewrite.remove("config").unwrap(), ["config/", "config/app.properties", "config/old.txt"]);
+        assert!(matches!(rewrite.remove("missing.txt"), Err(Error::EntryNotFound { .. })));
+        rewrite.commit().unwrap();
+
+        let after = snapshot(&jar);
+        assert_eq!(names(&after), ["streamed.txt", "META-INF/MANIFEST.MF", "lib/native.so"]);
+        assert_untouched(&before, &after, &["remove.me", "config/", "config/app.properties", "config/old.txt"]);
+    }
+
+    #[test]
+    fn rename_keeps_entry_metadata() {
+        let directory = TempDir::new().unwrap();
+        let jar = build_archive(&directory);
+        let before = snapshot(&jar);
+        let streamed_record = raw_record(&jar, "streamed.txt");
+
+        let mut rewrite = ArchiveRewrite::open(&jar).unwrap();
+        assert_eq!(rewrite.rename("config/old.txt", "config/new.txt").unwrap(),
+            [("config/old.txt".to_string(), "config/new.txt".to_string())]);
+        assert_eq!(rewrite.rename("streamed.txt", "docs/").unwrap(),
+            [("streamed.txt".to_string(), "docs/streamed.txt".to_string())]);
+        assert!(matches!(rewrite.rename("remove.me", "config/app.properties"), Err(Error::EntryExists { .. })));
+        rewrite.commit().unwrap();
+
+        let after = snapshot(&jar);
+        assert_eq!(names(&after), ["docs/streamed.txt", "META-INF/MANIFEST.MF", "lib/native.so", "config/",
+            "config/app.properties", "config/new.txt", "remove.me"]);
+        assert_untouched(&before, &after, &["config/old.txt", "config/new.txt", "streamed.txt", "docs/streamed.txt"]);
+        for (old_name, new_name) in [("config/old.txt", "config/new.txt"), ("streamed.txt", "docs/streamed.txt")] {
+            let (original, renamed) = (find(&before, old_name), find(&after, new_name));
+            assert_eq!((renamed.compression, renamed.crc32, renamed.modified), (original.compression, original.crc32, original.modified));
+            assert_eq!(renamed.contents, original.contents);
+        }
+
+        // Only the name in the local header changed, the data descriptor is still there
+        let renamed_record = raw_record(&jar, "docs/streamed.txt");
+        let old_header_len = LOCAL_HEADER_LEN + "streamed.txt".len();
+        let new_header_len = LOCAL_HEADER_LEN + "docs/streamed.txt".len();
+        assert_eq!(renamed_record[..26], streamed_record[..26]);
+        assert_eq!(renamed_record[new_header_len..], streamed_record[old_header_len..]);
+    }
+
+    #[test]
+    fn rename_directory() {
+        let directory = TempDir::new().unwrap();
+        let jar = build_archive(&directory);
+
+        let mut rewrite = ArchiveRewrite::open(&jar).unwrap();
+        assert_eq!(rewrite.rename("config", "settings").unwrap().len(), 3);
+        assert!(matches!(rewrite.rename("settings", "settings/nested"), Err(Error::InvalidPath { .. })));
+        rewrite.commit().unwrap();
+
+        let after = snapshot(&jar);
+        assert_eq!(names(&after)[3..6], ["settings/", "settings/app.properties", "settings/old.txt"]);
+        assert_eq!(find(&after, "settings/app.properties").contents, b"timeout=30\n");
+    }
+
+    #[test]
+    fn add_appends_entry() {
+        let directory = TempDir::new().unwrap();
+        let jar = build_archive(&directory);
+        let before = snapshot(&jar);
+
+        let mut rewrite = ArchiveRewrite::open(&jar).unwrap();
+        rewrite.add("config/extra.properties", b"retries=3\n".repeat(10), EntryCompression::default()).unwrap();
+        rewrite.add("stored.txt", b"as is\n".to_vec(), EntryCompression { method: CompressionMethod::Stored, level: 0 }).unwrap();
+        assert!(matches!(rewrite.add("remove.me", Vec::new(), EntryCompression::default()), Err(Error::EntryExists { .. })));
+        rewrite.commit().unwrap();
+
+        let after = snapshot(&jar);
+        assert_eq!(after[..before.len()], before[..]);
+        let added = find(&after, "config/extra.properties");
+        assert_eq!(added.contents, b"retries=3\n".repeat(10));
+        assert_eq!(added.compression, zip::CompressionMethod::Deflated);
+        assert_eq!(find(&after, "stored.txt").compression, zip::CompressionMethod::Stored);
+        assert_eq!(names(&after)[before.len()..], ["config/extra.properties", "stored.txt"]);
+    }
+
+    #[test]
+    fn changes_lists_every_change() {
+        let directory = TempDir::new().unwrap();
+        let jar = build_archive(&directory);
+
+        let mut rewrite = ArchiveRewrite::open(&jar).unwrap();
+        rewrite.replace("config/app.properties", b"timeout=60\n".to_vec(), None).unwrap();
+        rewrite.rename("config/old.txt", "config/new.txt").unwrap();
+        rewrite.remove("remove.me").unwrap();
+        rewrite.add("added.txt", b"added\n".to_vec(), EntryCompression::default()).unwrap();
+
+        let changes = rewrite.changes().iter()
+            .map(|change| match change {
+                EntryChange::Added { name, .. } => format!("A {}", name),
+                EntryChange::Removed { name } => format!("D {}", name),
+                EntryChange::Modified { name, .. } => format!("M {}", name),
+                EntryChange::Renamed { original_name, name } => format!("R {} {}", original_name, name),
+            })
+            .collect::<Vec<String>>();
+        assert_eq!(changes, ["M config/app.properties", "R config/old.txt config/new.txt", "A added.txt", "D remove.me"]);
+    }
+}
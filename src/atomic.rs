@@ -0,0 +1,89 @@
+use log::info;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io;
+use std::path::{Path, PathBuf};
+use tempfile::Builder;
+
+use crate::{Error, Result};
+
+/// Advisory lock serializing the commands that change a JAR. It is held on a `<jar>.lock` file
+/// next to the JAR rather than the JAR itself, since every write replaces the JAR with a new
+/// file. The lock is released when dropped, or by the system when the process dies.
+pub struct JarLock {
+    _file: File,
+}
+
+impl JarLock {
+    /// Lock the JAR, waiting for other processes holding the lock
+    pub fn acquire<P: AsRef<Path>>(jar: P) -> Result<JarLock> {
+        let path = suffixed_path(jar.as_ref(), ".lock");
+        let file = OpenOptions::new().create(true).truncate(false).write(true).open(&path)
+            .map_err(|err| Error::io(format!("Unable to open lock file {:?}", path), err))?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                info!("Waiting for another sicas_audit to release {:?}", path);
+                file.lock().map_err(|err| Error::io(format!("Unable to lock {:?}", path), err))?;
+            }
+            Err(TryLockError::Error(err)) => return Err(Error::io(format!("Unable to lock {:?}", path), err)),
+        }
+
+        Ok(JarLock { _file: file })
+    }
+}
+
+/// Replace `target` with the contents written by `write`, so that it is either entirely the old
+/// or entirely the new file, even if the process is killed. The contents go to a temporary file
+/// in the same directory, which is synced to disk, given the permissions of `target` and then
+/// renamed over it.
+pub fn replace_file<F>(target: &Path, write: F) -> Result<()>
+where
+    F: FnOnce(&mut File) -> Result<()>,
+{
+    let directory = parent_directory(target);
+    let file_name = target.file_name()
+        .ok_or_else(|| Error::io(format!("Unable to replace {:?}", target), io::Error::new(io::ErrorKind::InvalidInput, "not a file")))?;
+    let mut prefix = OsString::from(".");
+    prefix.push(file_name);
+    prefix.push(".");
+
+    let mut temp_file = Builder::new()
+        .prefix(&prefix)
+        .suffix(".tmp")
+        .tempfile_in(directory)
+        .map_err(|err| Error::io(format!("Unable to create a temporary file in {:?}", directory), err))?;
+    write(temp_file.as_file_mut())?;
+    temp_file.as_file().sync_all()?;
+
+    if let Ok(metadata) = fs::metadata(target) {
+        temp_file.as_file().set_permissions(metadata.permissions())?;
+    }
+    temp_file.persist(target)
+        .map_err(|err| Error::io(format!("Unable to replace {:?}", target), err.error))?;
+
+    sync_directory(directory)
+}
+
+/// Make the rename durable. Directories cannot be opened as files on Windows, where the rename
+/// itself is already durable.
+fn sync_directory(directory: &Path) -> Result<()> {
+    if cfg!(unix) {
+        File::open(directory)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Directory containing `path`, `.` for a bare file name
+pub fn parent_directory(path: &Path) -> &Path {
+    path.parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."))
+}
+
+fn suffixed_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
@@ -0,0 +1,450 @@
+use chrono::{Local, NaiveDate, NaiveDateTime, Timelike};
+use regex::Regex;
+use serde::{Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+
+/// Format used when writing timestamps to the audit trail
+pub const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// Timestamp formats accepted when reading the audit trail
+const ACCEPTED_TIMESTAMP_FORMATS: [&str; 3] = [TIMESTAMP_FORMAT, "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M"];
+
+const FIELD_SEPARATOR: char = '|';
+const COMMENT_MARKER: char = '#';
+const EMPTY_COMMENT: &str = "";
+
+/// Prefix of the trailing hash field of chained entries
+const HASH_PREFIX: &str = "sha256:";
+
+/// Previous hash used for the first entry of a hash chain
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// A single line of the audit trail:
+/// `2022-01-31 14:05:00 | goodwir | EDIT | config.properties | Raised the session timeout`
+///
+/// Entries appended by sicas_audit end with an extra `| sha256:<hash>` field that chains them
+/// to the entry before, see [`AuditEntry::chain_hash`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AuditEntry {
+    #[serde(rename = "line")]
+    pub line_number: usize,
+    pub timestamp: NaiveDateTime,
+    pub user: String,
+    pub action: Action,
+    pub target: String,
+    pub comment: String,
+    pub hash: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Add,
+    Edit,
+    Delete,
+    Rename,
+    Deploy,
+    Other(String),
+}
+
+/// A line of the audit trail that could not be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MalformedLine {
+    pub line_number: usize,
+    pub reason: String,
+}
+
+#[derive(Debug, Default)]
+pub struct AuditTrail {
+    pub entries: Vec<AuditEntry>,
+    pub malformed_lines: Vec<MalformedLine>,
+}
+
+/// Result of walking the hash chain of an audit trail
+#[derive(Debug, Default)]
+pub struct ChainVerification {
+    /// Entries whose hash matched
+    pub verified: usize,
+    /// Entries written before the chain started, which are not covered by it
+    pub unchained: usize,
+    /// The first link that does not hold, if any
+    pub broken_link: Option<MalformedLine>,
+}
+
+impl AuditTrail {
+    /// Parse the audit trail. Blank lines and lines starting with `#` are skipped,
+    /// every other line must be a complete entry.
+    pub fn parse(contents: &str) -> AuditTrail {
+        let mut audit_trail = AuditTrail::default();
+
+        for (index, line) in contents.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(COMMENT_MARKER) {
+                continue;
+            }
+
+            match AuditEntry::parse(line_number, line) {
+                Ok(entry) => audit_trail.entries.push(entry),
+                Err(reason) => audit_trail.malformed_lines.push(MalformedLine { line_number, reason }),
+            }
+        }
+
+        audit_trail
+    }
+
+    /// Hash of the last chained entry, which the next appended entry has to chain to
+    pub fn last_hash(&self) -> &str {
+        self.entries.iter()
+            .rev()
+            .find_map(|entry| entry.hash.as_deref())
+            .unwrap_or(GENESIS_HASH)
+    }
+
+    /// Walk the hash chain. The chain starts at the first hashed entry, every line after it
+    /// has to be a hashed entry that chains to the one before.
+    pub fn verify_chain(&self) -> ChainVerification {
+        let mut verification = ChainVerification::default();
+        let mut previous_hash: Option<&str> = None;
+
+        for entry in &self.entries {
+            let broken_reason = match (&entry.hash, previous_hash) {
+                (None, None) => {
+                    verification.unchained += 1;
+                    continue;
+                }
+                (None, Some(_)) => Some("entry has no hash but follows chained entries".to_string()),
+                (Some(hash), previous) => {
+                    let expected = entry.chain_hash(previous.unwrap_or(GENESIS_HASH));
+                    if *hash == expected {
+                        None
+                    } else {
+                        Some(format!("hash mismatch, expected {}{}. The entry or one before it was altered or removed", HASH_PREFIX, expected))
+                    }
+                }
+            };
+
+            if let Some(reason) = broken_reason {
+                verification.broken_link = Some(MalformedLine { line_number: entry.line_number, reason });
+                break;
+            }
+            verification.verified += 1;
+            previous_hash = entry.hash.as_deref();
+        }
+
+        let chain_start = self.entries.iter()
+            .find(|entry| entry.hash.is_some())
+            .map(|entry| entry.line_number);
+        let first_malformed_line = self.malformed_lines.iter()
+            .find(|malformed_line| chain_start.is_some_and(|start| malformed_line.line_number > start));
+        if let Some(malformed_line) = first_malformed_line {
+            let is_earlier = verification.broken_link.as_ref()
+                .is_none_or(|broken_link| malformed_line.line_number < broken_link.line_number);
+            if is_earlier {
+                verification.broken_link = Some(malformed_line.clone());
+            }
+        }
+
+        verification
+    }
+}
+
+/// Restricts which audit entries are shown. Every criterion that is set must match.
+#[derive(Debug, Default)]
+pub struct AuditFilter {
+    pub since: Option<NaiveDateTime>,
+    pub until: Option<NaiveDateTime>,
+    pub user: Option<String>,
+    pub action: Option<Action>,
+    pub pattern: Option<Regex>,
+}
+
+impl AuditFilter {
+    pub fn matches(&self, entry: &AuditEntry) -> bool {
+        self.since.is_none_or(|since| entry.timestamp >= since)
+            && self.until.is_none_or(|until| entry.timestamp <= until)
+            && self.user.as_ref().is_none_or(|user| entry.user.eq_ignore_ascii_case(user))
+            && self.action.as_ref().is_none_or(|action| &entry.action == action)
+            && self.pattern.as_ref().is_none_or(|pattern| pattern.is_match(&entry.to_string()))
+    }
+}
+
+impl AuditEntry {
+    /// Create an entry timestamped with the current local time, ready to be appended to the trail
+    pub fn new(user: &str, action: Action, target: &str, comment: &str) -> Result<AuditEntry, String> {
+        let now = Local::now().naive_local();
+        let entry = AuditEntry {
+            line_number: 0,
+            timestamp: now.with_nanosecond(0).unwrap_or(now),
+            user: single_field(user.trim(), "user")?,
+            action,
+            target: single_field(target.trim(), "target")?,
+            comment: comment.split_whitespace().collect::<Vec<&str>>().join(" "),
+            hash: None,
+        };
+
+        if entry.user.contains(char::is_whitespace) {
+            return Err(format!("invalid user {:?}, users cannot contain whitespace", entry.user));
+        }
+        Ok(entry)
+    }
+
+    fn parse(line_number: usize, line: &str) -> Result<AuditEntry, String> {
+        let fields = line.splitn(5, FIELD_SEPARATOR)
+            .map(str::trim)
+            .collect::<Vec<&str>>();
+        if fields.len() < 4 {
+            return Err(format!("expected at least 4 fields separated by '{}', found {}", FIELD_SEPARATOR, fields.len()));
+        }
+
+        let timestamp = parse_timestamp(fields[0])
+            .ok_or_else(|| format!("invalid timestamp {:?}, expected YYYY-MM-DD HH:MM:SS", fields[0]))?;
+        let user = non_empty_field(fields[1], "user")?;
+        let action = non_empty_field(fields[2], "action")?.parse::<Action>()?;
+        let target = non_empty_field(fields[3], "target")?;
+        let (comment, hash) = split_hash(fields.get(4).copied().unwrap_or_default());
+
+        Ok(AuditEntry {
+            line_number,
+            timestamp,
+            user,
+            action,
+            target,
+            comment: comment.to_string(),
+            hash: hash.map(str::to_string),
+        })
+    }
+
+    /// Hash linking this entry to the previous one: SHA-256 over the previous hash, a newline
+    /// and the entry as it is written to the trail without its own hash
+    pub fn chain_hash(&self, previous_hash: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(previous_hash.as_bytes());
+        hasher.update(b"\n");
+        hasher.update(self.unhashed_line().as_bytes());
+        format!("{:x}", hasher.finalize())
+    }
+
+    fn unhashed_line(&self) -> String {
+        format!("{} {sep} {} {sep} {} {sep} {} {sep} {}",
+            self.timestamp.format(TIMESTAMP_FORMAT), self.user, self.action, self.target, self.comment,
+            sep = FIELD_SEPARATOR)
+    }
+}
+
+impl fmt::Display for AuditEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.unhashed_line())?;
+        if let Some(hash) = &self.hash {
+            write!(f, " {} {}{}", FIELD_SEPARATOR, HASH_PREFIX, hash)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(action: &str) -> Result<Self, Self::Err> {
+        if action.contains(char::is_whitespace) {
+            return Err(format!("invalid action {:?}, actions cannot contain whitespace", action));
+        }
+
+        Ok(match action.to_ascii_uppercase().as_str() {
+            "ADD" => Action::Add,
+            "EDIT" => Action::Edit,
+            "DELETE" => Action::Delete,
+            "RENAME" => Action::Rename,
+            "DEPLOY" => Action::Deploy,
+            _ => Action::Other(action.to_ascii_uppercase()),
+        })
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Add => f.write_str("ADD"),
+            Action::Edit => f.write_str("EDIT"),
+            Action::Delete => f.write_str("DELETE"),
+            Action::Rename => f.write_str("RENAME"),
+            Action::Deploy => f.write_str("DEPLOY"),
+            Action::Other(action) => f.write_str(action),
+        }
+    }
+}
+
+impl Serialize for Action {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl fmt::Display for MalformedLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line_number, self.reason)
+    }
+}
+
+pub fn parse_timestamp(timestamp: &str) -> Option<NaiveDateTime> {
+    ACCEPTED_TIMESTAMP_FORMATS.iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(timestamp, format).ok())
+}
+
+/// Parse the lower bound of a date range. A bare date means the start of that day.
+pub fn parse_since(bound: &str) -> Result<NaiveDateTime, String> {
+    parse_date_bound(bound, |date| date.and_hms_opt(0, 0, 0))
+}
+
+/// Parse the upper bound of a date range. A bare date means the end of that day.
+pub fn parse_until(bound: &str) -> Result<NaiveDateTime, String> {
+    parse_date_bound(bound, |date| date.and_hms_opt(23, 59, 59))
+}
+
+fn parse_date_bound<F>(bound: &str, from_date: F) -> Result<NaiveDateTime, String>
+    where F: Fn(NaiveDate) -> Option<NaiveDateTime>
+{
+    parse_timestamp(bound)
+        .or_else(|| NaiveDate::parse_from_str(bound, "%Y-%m-%d").ok().and_then(from_date))
+        .ok_or_else(|| format!("invalid date {:?}, expected YYYY-MM-DD or YYYY-MM-DD HH:MM:SS", bound))
+}
+
+/// Split a trailing `| sha256:<hash>` field off the comment
+fn split_hash(comment: &str) -> (&str, Option<&str>) {
+    let (rest, field) = comment.rsplit_once(FIELD_SEPARATOR)
+        .unwrap_or((EMPTY_COMMENT, comment));
+
+    match field.trim().strip_prefix(HASH_PREFIX) {
+        Some(hash) if hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()) => (rest.trim_end(), Some(hash)),
+        _ => (comment, None),
+    }
+}
+
+fn single_field(field: &str, name: &str) -> Result<String, String> {
+    if field.contains(FIELD_SEPARATOR) || field.contains(['\r', '\n']) {
+        return Err(format!("invalid {} {:?}, it cannot contain '{}' or line breaks", name, field, FIELD_SEPARATOR));
+    }
+    non_empty_field(field, name)
+}
+
+fn non_empty_field(field: &str, name: &str) -> Result<String, String> {
+    if field.is_empty() {
+        Err(format!("missing {}", name))
+    } else {
+        Ok(field.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEGACY_LINE: &str = "2022-01-31 10:00:00 | goodwir | EDIT | config.properties | Written by hand";
+
+    fn entry(minute: u32, target: &str, comment: &str) -> AuditEntry {
+        AuditEntry {
+            line_number: 0,
+            timestamp: NaiveDate::from_ymd_opt(2022, 2, 1).unwrap().and_hms_opt(9, minute, 0).unwrap(),
+            user: "goodwir".to_string(),
+            action: Action::Edit,
+            target: target.to_string(),
+            comment: comment.to_string(),
+            hash: None,
+        }
+    }
+
+    /// Append every entry chained to the one before, the way entries are logged
+    fn append(mut contents: String, entries: Vec<AuditEntry>) -> String {
+        for mut entry in entries {
+            entry.hash = Some(entry.chain_hash(AuditTrail::parse(&contents).last_hash()));
+            contents.push_str(&format!("{}\n", entry));
+        }
+        contents
+    }
+
+    /// A hand-written entry followed by three chained ones, on lines 2 to 4
+    fn chained_trail() -> String {
+        append(format!("{}\n", LEGACY_LINE), vec![
+            entry(1, "web.xml", "Raised the session timeout"),
+            entry(2, "app.properties", "Pointed at the new database"),
+            entry(3, "log4j.xml", "Less logging"),
+        ])
+    }
+
+    fn broken_line(contents: &str) -> Option<usize> {
+        AuditTrail::parse(contents).verify_chain().broken_link.map(|broken_link| broken_link.line_number)
+    }
+
+    #[test]
+    fn intact_chain_verifies() {
+        let verification = AuditTrail::parse(&chained_trail()).verify_chain();
+        assert_eq!((verification.verified, verification.unchained), (3, 1));
+        assert_eq!(verification.broken_link, None);
+    }
+
+    #[test]
+    fn first_chained_entry_starts_at_genesis() {
+        let audit_trail = AuditTrail::parse(&chained_trail());
+        let first = &audit_trail.entries[1];
+        assert_eq!(first.hash.as_deref(), Some(first.chain_hash(GENESIS_HASH).as_str()));
+        assert_eq!(AuditTrail::parse("").last_hash(), GENESIS_HASH);
+    }
+
+    #[test]
+    fn edited_middle_entry_breaks_chain() {
+        let contents = chained_trail().replace("Pointed at the new database", "Pointed at the old database");
+        let broken_link = AuditTrail::parse(&contents).verify_chain().broken_link.unwrap();
+        assert_eq!(broken_link.line_number, 3);
+        assert!(broken_link.reason.starts_with("hash mismatch"), "{}", broken_link.reason);
+    }
+
+    #[test]
+    fn deleted_entry_breaks_chain() {
+        let contents = chained_trail().lines()
+            .filter(|line| !line.contains("app.properties"))
+            .map(|line| format!("{}\n", line))
+            .collect::<String>();
+        assert_eq!(broken_line(&contents), Some(3));
+    }
+
+    #[test]
+    fn unhashed_line_after_chain_start_breaks_chain() {
+        let contents = format!("{}{}\n", chained_trail(), LEGACY_LINE);
+        let broken_link = AuditTrail::parse(&contents).verify_chain().broken_link.unwrap();
+        assert_eq!(broken_link.line_number, 5);
+        assert_eq!(broken_link.reason, "entry has no hash but follows chained entries");
+    }
+
+    #[test]
+    fn malformed_line_after_chain_start_breaks_chain() {
+        let contents = format!("{}not an entry\n", chained_trail());
+        assert_eq!(broken_line(&contents), Some(5));
+        // Comments and blank lines are not entries
+        assert_eq!(broken_line(&format!("{}\n# reviewed\n", chained_trail())), None);
+    }
+
+    #[test]
+    fn comment_containing_a_hash_field() {
+        let fake_hash_comment = format!("Copied from the log | {}{}", HASH_PREFIX, "0".repeat(64));
+        let contents = append(chained_trail(), vec![entry(4, "web.xml", &fake_hash_comment)]);
+
+        let audit_trail = AuditTrail::parse(&contents);
+        let last = audit_trail.entries.last().unwrap();
+        assert_eq!(last.comment, fake_hash_comment);
+        assert_eq!(last.hash.as_deref(), Some(audit_trail.last_hash()));
+        assert_ne!(audit_trail.last_hash(), GENESIS_HASH);
+        assert_eq!(audit_trail.verify_chain().verified, 4);
+        assert_eq!(last.to_string(), contents.lines().last().unwrap());
+    }
+
+    #[test]
+    fn split_hash_takes_only_a_trailing_hash_field() {
+        let hash = "ab".repeat(32);
+        assert_eq!(split_hash(&format!("Comment | sha256:{}", hash)), ("Comment", Some(hash.as_str())));
+        assert_eq!(split_hash(&format!("sha256:{}", hash)), ("", Some(hash.as_str())));
+        assert_eq!(split_hash("Comment | sha256:abc"), ("Comment | sha256:abc", None));
+        assert_eq!(split_hash(&format!("Comment | sha256:{} trailing", hash)).1, None);
+        assert_eq!(split_hash("Comment | with a pipe"), ("Comment | with a pipe", None));
+    }
+}
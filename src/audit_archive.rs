@@ -0,0 +1,556 @@
+use chrono::{Local, NaiveDate, NaiveDateTime, TimeZone};
+use encoding_rs::Encoding;
+use globset::{GlobBuilder, GlobMatcher};
+use log::{debug, info, warn};
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use zip::read::ZipFile;
+
+use crate::archive::{is_signature_file, open_archive, ArchiveRewrite, EntryCompression};
+use crate::atomic::JarLock;
+use crate::audit::{AuditEntry, AuditTrail};
+use crate::backup::{Backups, DEFAULT_BACKUP_KEEP};
+use crate::encoding::{decode, encode, Contents};
+use crate::patterns::IgnoredFiles;
+use crate::{Error, Result};
+
+/// Name of the audit trail inside the JAR unless configured otherwise
+pub const DEFAULT_AUDIT_FILE: &str = "AUDIT_TRAIL";
+
+/// A JAR and the audit trail stored inside it.
+///
+/// Operations that change the JAR return a [`PendingChange`], which writes nothing until it is
+/// committed. Hold [`AuditArchive::lock`] from reading the JAR until the change is committed
+/// when other processes may change it as well.
+///
+/// ```no_run
+/// use sicas_audit::{audit::{Action, AuditEntry}, AuditArchive};
+///
+/// let archive = AuditArchive::open("sicas-billing.jar")?;
+/// let _lock = archive.lock()?;
+/// let entry = AuditEntry::new("goodwir", Action::Deploy, "sicas-billing.jar", "Release 2.4")
+///     .map_err(sicas_audit::Error::InvalidAuditEntry)?;
+/// let (change, _) = archive.append_entry(entry)?;
+/// change.commit()?;
+/// # Ok::<(), sicas_audit::Error>(())
+/// ```
+pub struct AuditArchive {
+    jar: PathBuf,
+    audit_file: String,
+    encoding: Option<&'static Encoding>,
+    backups: Backups,
+}
+
+/// A rewrite of the JAR that has not been written yet
+#[must_use = "changes are only written to the JAR when committed"]
+pub struct PendingChange<'a> {
+    archive: &'a AuditArchive,
+    rewrite: ArchiveRewrite,
+}
+
+/// An entry of the archive as listed by [`AuditArchive::list`]
+#[derive(Serialize)]
+pub struct ArchiveFile {
+    pub name: String,
+    pub size: u64,
+    pub compressed_size: u64,
+    pub compression: String,
+    pub crc32: String,
+    pub modified: Option<NaiveDateTime>,
+    pub permissions: Option<String>,
+    #[serde(skip)]
+    pub is_dir: bool,
+}
+
+impl AuditArchive {
+    /// Open a JAR, checking that it is a readable archive. The audit trail defaults to
+    /// [`DEFAULT_AUDIT_FILE`], its encoding is detected and backups are kept next to the JAR.
+    pub fn open<P: AsRef<Path>>(jar: P) -> Result<AuditArchive> {
+        let jar = jar.as_ref();
+        open_archive(jar)?;
+
+        Ok(AuditArchive {
+            jar: jar.to_path_buf(),
+            audit_file: DEFAULT_AUDIT_FILE.to_string(),
+            encoding: None,
+            backups: Backups::new(jar, None, DEFAULT_BACKUP_KEEP),
+        })
+    }
+
+    pub fn with_audit_file(mut self, audit_file: &str) -> AuditArchive {
+        self.audit_file = audit_file.to_string();
+        self
+    }
+
+    /// Decode text entries with this encoding instead of detecting it
+    pub fn with_encoding(mut self, encoding: Option<&'static Encoding>) -> AuditArchive {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Keep `keep` backups in `directory`, or next to the JAR. 0 disables backups.
+    pub fn with_backups(mut self, directory: Option<&Path>, keep: usize) -> AuditArchive {
+        self.backups = Backups::new(&self.jar, directory, keep);
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.jar
+    }
+
+    pub fn audit_file(&self) -> &str {
+        &self.audit_file
+    }
+
+    pub fn backups(&self) -> &Backups {
+        &self.backups
+    }
+
+    /// Wait for other processes changing the JAR, see [`JarLock`]
+    pub fn lock(&self) -> Result<JarLock> {
+        JarLock::acquire(&self.jar)
+    }
+
+    pub fn contains(&self, name: &str) -> Result<bool> {
+        Ok(open_archive(&self.jar)?.by_name(name).is_ok())
+    }
+
+    /// Every entry of the archive, directories included, in archive order
+    pub fn list(&self) -> Result<Vec<ArchiveFile>> {
+        let mut archive = open_archive(&self.jar)?;
+        let mut archive_files = Vec::with_capacity(archive.len());
+
+        for index in 0..archive.len() {
+            let file = archive.by_index(index).map_err(|err| Error::zip(&self.jar, err))?;
+            archive_files.push(ArchiveFile::from_zip_file(&file));
+        }
+
+        Ok(archive_files)
+    }
+
+    /// The contents of an entry, decompressed
+    pub fn read(&self, name: &str) -> Result<Vec<u8>> {
+        let mut archive = open_archive(&self.jar)?;
+        let mut archive_file = archive.by_name(name).map_err(|err| match err {
+            zip::result::ZipError::FileNotFound => Error::EntryNotFound { jar: self.jar.clone(), name: name.to_string() },
+            err => Error::zip(&self.jar, err),
+        })?;
+
+        let mut contents = Vec::new();
+        archive_file.read_to_end(&mut contents)
+            .map_err(|err| Error::io(format!("Unable to read {:?} from {:?}", name, self.jar), err))?;
+        Ok(contents)
+    }
+
+    /// The contents of a text entry, decoded
+    pub fn read_text(&self, name: &str) -> Result<String> {
+        Ok(self.decode_text(name, &self.read(name)?)?.0)
+    }
+
+    /// The bytes of the audit trail, undecoded
+    pub fn read_audit_file(&self) -> Result<Vec<u8>> {
+        self.read(&self.audit_file).map_err(|err| match err {
+            Error::EntryNotFound { jar, name } => Error::AuditFileNotFound { jar, name },
+            err => err,
+        })
+    }
+
+    pub fn audit_trail(&self) -> Result<AuditTrail> {
+        let (text, _) = self.decode_text(&self.audit_file, &self.read_audit_file()?)?;
+        Ok(AuditTrail::parse(&text))
+    }
+
+    /// Replace the contents of an existing entry
+    pub fn edit(&self, name: &str, contents: Vec<u8>) -> Result<PendingChange<'_>> {
+        let mut rewrite = ArchiveRewrite::open(&self.jar)?;
+        rewrite.replace(name, contents, None)?;
+        Ok(self.pending(rewrite))
+    }
+
+    /// Add an entry, or replace it when it exists and `replace` is set. Changing the manifest or
+    /// signature files of a signed JAR requires `force`.
+    pub fn add(&self, name: &str, contents: Vec<u8>, compression: EntryCompression, replace: bool, force: bool) -> Result<PendingChange<'_>> {
+        validate_archive_path(name)?;
+
+        let mut rewrite = ArchiveRewrite::open(&self.jar)?;
+        let replaced = rewrite.contains(name);
+        if replaced && !replace {
+            return Err(Error::EntryExists { jar: self.jar.clone(), name: name.to_string() });
+        }
+
+        if rewrite.is_signed() {
+            if is_signature_file(name) {
+                if !force {
+                    return Err(Error::SignatureFile { jar: self.jar.clone(), name: name.to_string() });
+                }
+                warn!("{:?} is signed, changing {} invalidates its signature", self.jar, name);
+            } else if replaced {
+                warn!("{:?} is signed, its signature will no longer match {}", self.jar, name);
+            } else {
+                warn!("{:?} is signed, {} will not be covered by its signature", self.jar, name);
+            }
+        }
+        if replaced {
+            rewrite.replace(name, contents, Some(compression))?;
+        } else {
+            rewrite.add(name, contents, compression)?;
+        }
+        Ok(self.pending(rewrite))
+    }
+
+    /// Remove an entry, or a directory with everything below it. Also returns the names of the
+    /// removed entries.
+    pub fn delete(&self, name: &str) -> Result<(PendingChange<'_>, Vec<String>)> {
+        let mut rewrite = ArchiveRewrite::open(&self.jar)?;
+        let removed = rewrite.remove(name)?;
+        Ok((self.pending(rewrite), removed))
+    }
+
+    /// Rename `from` to `to` and record the entry built by `audit_entry` from the old and new
+    /// names of the moved entries in the audit trail, in a single rewrite. A file renamed to a
+    /// name ending in `/` is moved into that directory. The audit trail is created when the JAR
+    /// has none yet. Also returns the old and new name of every moved entry.
+    pub fn rename<F>(&self, from: &str, to: &str, audit_entry: F) -> Result<(PendingChange<'_>, Vec<(String, String)>)>
+    where
+        F: FnOnce(&[(String, String)]) -> Result<AuditEntry>,
+    {
+        let existing_audit_trail = self.existing_audit_file()?;
+        let audit_trail_exists = existing_audit_trail.is_some();
+        let mut audit_trail = existing_audit_trail.unwrap_or_default();
+        let mut rewrite = ArchiveRewrite::open(&self.jar)?;
+        let renames = rewrite.rename(from, to)?;
+        for (old_name, new_name) in &renames {
+            // Only directory entries may end in a slash
+            validate_archive_path(if old_name.ends_with('/') { new_name.trim_end_matches('/') } else { new_name })?;
+        }
+        let mut entry = audit_entry(&renames)?;
+        self.chain_audit_entry(&mut audit_trail, &mut entry)?;
+
+        // The audit trail may have been moved along with its directory
+        let audit_file = renames.iter()
+            .find(|(old_name, _)| *old_name == self.audit_file)
+            .map_or(self.audit_file.as_str(), |(_, new_name)| new_name.as_str());
+        self.write_audit_file(&mut rewrite, audit_file, audit_trail, audit_trail_exists)?;
+        Ok((self.pending(rewrite), renames))
+    }
+
+    /// Append `entry` to the audit trail, chained to its last entry. A JAR without an audit trail
+    /// gets one, starting with `entry` chained to [`GENESIS_HASH`]. Also returns the entry with
+    /// its hash.
+    ///
+    /// [`GENESIS_HASH`]: crate::audit::GENESIS_HASH
+    pub fn append_entry(&self, mut entry: AuditEntry) -> Result<(PendingChange<'_>, AuditEntry)> {
+        let existing_audit_trail = self.existing_audit_file()?;
+        let audit_trail_exists = existing_audit_trail.is_some();
+        let mut audit_trail = existing_audit_trail.unwrap_or_default();
+        self.chain_audit_entry(&mut audit_trail, &mut entry)?;
+
+        let mut rewrite = ArchiveRewrite::open(&self.jar)?;
+        self.write_audit_file(&mut rewrite, &self.audit_file, audit_trail, audit_trail_exists)?;
+        Ok((self.pending(rewrite), entry))
+    }
+
+    /// Extract the entries matching any of `patterns`, or all of them, to `destination`, keeping
+    /// their paths and modification times. Returns the number of extracted files.
+    ///
+    /// Patterns are entry names, directories or globs such as `**/*.properties`. Every pattern
+    /// must match an entry, and no entry may lead outside of `destination`; both are checked
+    /// before anything is written.
+    pub fn extract(&self, patterns: &[String], destination: &Path, ignored_files: Option<&IgnoredFiles>, overwrite: bool) -> Result<usize> {
+        let selectors = patterns.iter()
+            .map(|pattern| EntrySelector::new(pattern))
+            .collect::<Result<Vec<EntrySelector>>>()?;
+        let mut archive = open_archive(&self.jar)?;
+
+        let mut selected_entries = Vec::new();
+        let mut matched_selectors = vec![false; selectors.len()];
+        for index in 0..archive.len() {
+            let file = archive.by_index(index).map_err(|err| Error::zip(&self.jar, err))?;
+            let mut selected = selectors.is_empty();
+            for (selector, matched) in selectors.iter().zip(matched_selectors.iter_mut()) {
+                if selector.matches(file.name()) {
+                    *matched = true;
+                    selected = true;
+                }
+            }
+            if !selected || ignored_files.is_some_and(|ignored_files| ignored_files.is_ignored(file.name(), file.is_dir())) {
+                continue;
+            }
+
+            let relative_path = file.enclosed_name()
+                .ok_or_else(|| Error::InvalidPath {
+                    name: file.name().to_string(),
+                    reason: "it would be extracted outside of the destination".to_string(),
+                })?;
+            selected_entries.push((index, destination.join(relative_path)));
+        }
+
+        let unmatched_pattern = selectors.iter()
+            .zip(&matched_selectors)
+            .find(|(_, matched)| !**matched);
+        if let Some((selector, _)) = unmatched_pattern {
+            return Err(Error::EntryNotFound { jar: self.jar.clone(), name: selector.pattern.clone() });
+        }
+
+        let mut directory_times = Vec::new();
+        let mut extracted_files = 0;
+        for (index, path) in selected_entries {
+            let mut file = archive.by_index(index).map_err(|err| Error::zip(&self.jar, err))?;
+            let modified = zip_date_time(file.last_modified())
+                .and_then(|modified| Local.from_local_datetime(&modified).earliest())
+                .map(SystemTime::from);
+
+            if file.is_dir() {
+                fs::create_dir_all(&path).map_err(|err| Error::io(format!("Unable to create {:?}", path), err))?;
+                directory_times.push((path, modified));
+                continue;
+            }
+
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).map_err(|err| Error::io(format!("Unable to create {:?}", parent), err))?;
+            }
+            let mut output = OpenOptions::new().write(true).create(true).truncate(true).create_new(!overwrite).open(&path)
+                .map_err(|err| Error::io(format!("Unable to create {:?}", path), err))?;
+            io::copy(&mut file, &mut output)
+                .map_err(|err| Error::io(format!("Unable to extract {:?} to {:?}", file.name(), path), err))?;
+            if let Some(modified) = modified {
+                output.set_modified(modified)?;
+            }
+
+            debug!("Extracted {} to {:?}", file.name(), path);
+            extracted_files += 1;
+        }
+
+        // Extracting files into a directory updates its modification time, so restore those last
+        for (path, modified) in directory_times {
+            if let Some(modified) = modified {
+                if let Err(err) = File::open(&path).and_then(|directory| directory.set_modified(modified)) {
+                    debug!("Unable to set the modification time of {:?}: {}", path, err);
+                }
+            }
+        }
+
+        Ok(extracted_files)
+    }
+
+    fn pending(&self, rewrite: ArchiveRewrite) -> PendingChange<'_> {
+        PendingChange { archive: self, rewrite }
+    }
+
+    /// The audit trail, or `None` when the JAR has none yet
+    fn existing_audit_file(&self) -> Result<Option<Vec<u8>>> {
+        match self.read_audit_file() {
+            Ok(audit_trail) => Ok(Some(audit_trail)),
+            Err(Error::AuditFileNotFound { .. }) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn write_audit_file(&self, rewrite: &mut ArchiveRewrite, audit_file: &str, audit_trail: Vec<u8>, exists: bool) -> Result<()> {
+        if exists {
+            return rewrite.replace(audit_file, audit_trail, None);
+        }
+
+        info!("Starting audit trail {} in {:?}", audit_file, self.jar);
+        rewrite.add(audit_file, audit_trail, EntryCompression::default())
+    }
+
+    /// Append `entry` to the audit trail, chained to its last entry. The entry is written in the
+    /// encoding of the file, leaving the existing bytes untouched.
+    fn chain_audit_entry(&self, audit_trail: &mut Vec<u8>, entry: &mut AuditEntry) -> Result<()> {
+        let (text, encoding) = self.decode_text(&self.audit_file, audit_trail)?;
+        let previous_hash = AuditTrail::parse(&text).last_hash().to_string();
+        entry.hash = Some(entry.chain_hash(&previous_hash));
+
+        let separator = if text.is_empty() || text.ends_with('\n') { "" } else { "\n" };
+        let line = encode(&format!("{}{}\n", separator, entry), encoding)
+            .ok_or_else(|| Error::InvalidAuditEntry(format!("{} is {} encoded, which cannot represent every character of the entry",
+                self.audit_file, encoding.name())))?;
+        audit_trail.extend_from_slice(&line);
+        Ok(())
+    }
+
+    fn decode_text(&self, name: &str, contents: &[u8]) -> Result<(String, &'static Encoding)> {
+        match decode(contents, self.encoding) {
+            Contents::Text { text, encoding, had_errors } => {
+                log_decoding(name, encoding, had_errors);
+                Ok((text, encoding))
+            }
+            Contents::Binary => Err(Error::NotText(name.to_string())),
+        }
+    }
+}
+
+impl PendingChange<'_> {
+    pub fn rewrite(&self) -> &ArchiveRewrite {
+        &self.rewrite
+    }
+
+    /// Back up the JAR and write the change over it. Returns the path of the backup, if one
+    /// was taken.
+    pub fn commit(self) -> Result<Option<PathBuf>> {
+        let backup = self.archive.backups.create()?;
+        self.rewrite.commit()?;
+        Ok(backup)
+    }
+}
+
+impl ArchiveFile {
+    fn from_zip_file(file: &ZipFile) -> ArchiveFile {
+        ArchiveFile {
+            name: file.name().to_owned(),
+            size: file.size(),
+            compressed_size: file.compressed_size(),
+            compression: file.compression().to_string(),
+            crc32: format!("{:08x}", file.crc32()),
+            modified: zip_date_time(file.last_modified()),
+            permissions: file.unix_mode().map(|mode| format!("{:04o}", mode & 0o7777)),
+            is_dir: file.is_dir(),
+        }
+    }
+}
+
+fn log_decoding(name: &str, encoding: &'static Encoding, had_errors: bool) {
+    debug!("Decoding {} as {}", name, encoding.name());
+    if had_errors {
+        warn!("{} is not valid {}, undecodable bytes were replaced", name, encoding.name());
+    }
+}
+
+/// Reject entry names that would be extracted outside of the destination or are not files
+fn validate_archive_path(name: &str) -> Result<()> {
+    let invalid = name.is_empty()
+        || name.starts_with('/')
+        || name.ends_with('/')
+        || name.contains('\\')
+        || name.split('/').any(|component| component.is_empty() || component == "." || component == "..");
+    if invalid {
+        return Err(Error::InvalidPath {
+            name: name.to_string(),
+            reason: "expected a relative path such as \"config/app.properties\"".to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Convert the MS-DOS timestamp of an entry, which is in local time
+fn zip_date_time(date_time: zip::DateTime) -> Option<NaiveDateTime> {
+    NaiveDate::from_ymd_opt(date_time.year() as i32, date_time.month() as u32, date_time.day() as u32)
+        .and_then(|date| date.and_hms_opt(date_time.hour() as u32, date_time.minute() as u32, date_time.second() as u32))
+}
+
+/// Selects archive entries by exact name, by directory or by glob pattern
+struct EntrySelector {
+    pattern: String,
+    glob: GlobMatcher,
+}
+
+impl EntrySelector {
+    fn new(pattern: &str) -> Result<EntrySelector> {
+        let glob = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(|err| Error::InvalidPattern { pattern: pattern.to_string(), reason: err.kind().to_string() })?
+            .compile_matcher();
+
+        Ok(EntrySelector { pattern: pattern.to_string(), glob })
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        let directory = self.pattern.trim_end_matches('/');
+        name == self.pattern
+            || name.strip_prefix(directory).is_some_and(|rest| rest.starts_with('/'))
+            || self.glob.is_match(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::TempDir;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    /// An archive holding `entries`, written in order
+    fn build_archive(directory: &TempDir, entries: &[(&str, &[u8])]) -> AuditArchive {
+        let jar = directory.path().join("app.jar");
+        let mut writer = ZipWriter::new(File::create(&jar).unwrap());
+        for (name, contents) in entries {
+            if name.ends_with('/') {
+                writer.add_directory(*name, FileOptions::default()).unwrap();
+            } else {
+                writer.start_file(*name, FileOptions::default()).unwrap();
+                writer.write_all(contents).unwrap();
+            }
+        }
+        writer.finish().unwrap();
+        AuditArchive::open(jar).unwrap()
+    }
+
+    fn patterns(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|pattern| pattern.to_string()).collect()
+    }
+
+    #[test]
+    fn extract_selected_entries() {
+        let directory = TempDir::new().unwrap();
+        let archive = build_archive(&directory, &[
+            ("config/", b""),
+            ("config/app.properties", b"timeout=30\n"),
+            ("lib/native.so", b"\x7fELF"),
+            ("META-INF/MANIFEST.MF", b"Manifest-Version: 1.0\n"),
+        ]);
+        let destination = directory.path().join("out");
+
+        assert_eq!(archive.extract(&patterns(&["config", "META-INF/*.MF"]), &destination, None, false).unwrap(), 2);
+        assert_eq!(fs::read(destination.join("config/app.properties")).unwrap(), b"timeout=30\n");
+        assert!(destination.join("META-INF/MANIFEST.MF").is_file());
+        assert!(!destination.join("lib").exists());
+    }
+
+    #[test]
+    fn extract_rejects_entries_outside_of_destination() {
+        for name in ["../../escape.txt", "/absolute/escape.txt"] {
+            let directory = TempDir::new().unwrap();
+            let archive = build_archive(&directory, &[("safe.txt", b"safe"), (name, b"escaped")]);
+            let destination = directory.path().join("out/nested");
+
+            let result = archive.extract(&[], &destination, None, false);
+            assert!(matches!(result, Err(Error::InvalidPath { name: ref invalid, .. }) if invalid == name), "{}", name);
+            // Nothing is written, not even the entries before the offending one
+            assert!(!directory.path().join("out").exists(), "{}", name);
+            assert!(!directory.path().join("escape.txt").exists(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn extract_requires_every_pattern_to_match() {
+        let directory = TempDir::new().unwrap();
+        let archive = build_archive(&directory, &[("config/app.properties", b"timeout=30\n")]);
+        let destination = directory.path().join("out");
+
+        let result = archive.extract(&patterns(&["config/", "**/*.xml"]), &destination, None, false);
+        assert!(matches!(result, Err(Error::EntryNotFound { name, .. }) if name == "**/*.xml"));
+        assert!(!destination.exists());
+    }
+
+    #[test]
+    fn extract_overwrites_only_when_asked() {
+        let directory = TempDir::new().unwrap();
+        let archive = build_archive(&directory, &[("app.properties", b"timeout=30\n")]);
+        let destination = directory.path().join("out");
+        fs::create_dir(&destination).unwrap();
+        fs::write(destination.join("app.properties"), b"local changes\n").unwrap();
+
+        let result = archive.extract(&[], &destination, None, false);
+        assert!(matches!(result, Err(Error::Io { ref source, .. }) if source.kind() == io::ErrorKind::AlreadyExists));
+        assert_eq!(fs::read(destination.join("app.properties")).unwrap(), b"local changes\n");
+
+        assert_eq!(archive.extract(&[], &destination, None, true).unwrap(), 1);
+        assert_eq!(fs::read(destination.join("app.properties")).unwrap(), b"timeout=30\n");
+    }
+}
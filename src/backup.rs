@@ -0,0 +1,227 @@
+use chrono::{Local, NaiveDateTime};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::atomic::{parent_directory, replace_file};
+use crate::{Error, Result};
+
+/// Directory next to the JAR holding its backups when `BACKUP_DIR` is not set
+const DEFAULT_BACKUP_DIRECTORY: &str = ".sicas_audit_backups";
+const BACKUP_TIMESTAMP_FORMAT: &str = "%Y%m%d-%H%M%S%.3f";
+const BACKUP_EXTENSION: &str = ".bak";
+/// Hex digits of the JAR path hash in backup names
+const JAR_KEY_LENGTH: usize = 12;
+
+/// Number of backups kept per JAR unless configured otherwise
+pub const DEFAULT_BACKUP_KEEP: usize = 10;
+
+/// Timestamped copies of a JAR taken before it is modified, named
+/// `<jar name>.<key>.<YYYYMMDD-HHMMSS.mmm>.bak`. The key is a hash of the canonical path of the
+/// JAR, so JARs of the same name sharing a backup directory keep apart.
+pub struct Backups {
+    jar: PathBuf,
+    directory: PathBuf,
+    /// Start of the names of the backups of this JAR, `<jar name>.<key>.`
+    prefix: String,
+    /// Number of backups kept per JAR, 0 disables backups
+    keep: usize,
+}
+
+#[derive(Serialize)]
+pub struct Backup {
+    /// 1 for the newest backup
+    pub number: usize,
+    pub created: NaiveDateTime,
+    pub size: u64,
+    pub path: PathBuf,
+}
+
+impl Backups {
+    pub fn new<P: AsRef<Path>>(jar: P, directory: Option<&Path>, keep: usize) -> Backups {
+        let jar = jar.as_ref().to_path_buf();
+        let directory = match directory {
+            Some(directory) => directory.to_path_buf(),
+            None => parent_directory(&jar).join(DEFAULT_BACKUP_DIRECTORY),
+        };
+
+        let prefix = format!("{}.{}.", jar_name(&jar), jar_key(&jar));
+        Backups { jar, directory, prefix, keep }
+    }
+
+    /// Copy the JAR into the backup directory and remove backups beyond the retention count.
+    /// Returns the path of the backup, or `None` when backups are disabled.
+    pub fn create(&self) -> Result<Option<PathBuf>> {
+        if self.keep == 0 {
+            return Ok(None);
+        }
+
+        fs::create_dir_all(&self.directory)
+            .map_err(|err| Error::io(format!("Unable to create backup directory {:?}", self.directory), err))?;
+        let backup_path = self.directory.join(format!("{}{}{}",
+            self.prefix, Local::now().format(BACKUP_TIMESTAMP_FORMAT), BACKUP_EXTENSION));
+        fs::copy(&self.jar, &backup_path)
+            .map_err(|err| Error::io(format!("Unable to back up {:?} to {:?}", self.jar, backup_path), err))?;
+
+        for backup in self.list()?.iter().skip(self.keep) {
+            fs::remove_file(&backup.path)
+                .map_err(|err| Error::io(format!("Unable to remove old backup {:?}", backup.path), err))?;
+        }
+        Ok(Some(backup_path))
+    }
+
+    /// Backups of the JAR, newest first
+    pub fn list(&self) -> Result<Vec<Backup>> {
+        let entries = match fs::read_dir(&self.directory) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(Error::io(format!("Unable to read backup directory {:?}", self.directory), err)),
+        };
+
+        let mut backups = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            let created = file_name.to_str()
+                .and_then(|file_name| file_name.strip_prefix(&self.prefix))
+                .and_then(|file_name| file_name.strip_suffix(BACKUP_EXTENSION))
+                .and_then(|timestamp| NaiveDateTime::parse_from_str(timestamp, BACKUP_TIMESTAMP_FORMAT).ok());
+            if let Some(created) = created {
+                backups.push(Backup { number: 0, created, size: entry.metadata()?.len(), path: entry.path() });
+            }
+        }
+
+        backups.sort_by_key(|backup| std::cmp::Reverse(backup.created));
+        for (index, backup) in backups.iter_mut().enumerate() {
+            backup.number = index + 1;
+        }
+        Ok(backups)
+    }
+
+    /// Find a backup by its number in [`Backups::list`], its file name or its path
+    pub fn find(&self, backup: &str) -> Result<Backup> {
+        let backups = self.list()?;
+        let found = match backup.parse::<usize>() {
+            Ok(number) => backups.into_iter().find(|candidate| candidate.number == number),
+            Err(_) => backups.into_iter().find(|candidate| candidate.path == Path::new(backup)
+                || candidate.path.file_name().is_some_and(|file_name| file_name == backup)),
+        };
+
+        found.ok_or_else(|| Error::BackupNotFound {
+            jar: self.jar.clone(),
+            backup: backup.to_string(),
+            directory: self.directory.clone(),
+        })
+    }
+
+    /// Replace the JAR with a backup. The current JAR is backed up first, so a restore can be
+    /// undone as well. A JAR that was deleted is restored without that backup.
+    pub fn restore(&self, backup: &Backup) -> Result<Option<PathBuf>> {
+        let mut current_backup = None;
+        replace_file(&self.jar, |output| {
+            io::copy(&mut File::open(&backup.path)?, output)
+                .map_err(|err| Error::io(format!("Unable to read backup {:?}", backup.path), err))?;
+            // Only back up once the backup is copied, since taking a new one may prune it
+            if self.jar.exists() {
+                current_backup = self.create()?;
+            }
+            Ok(())
+        })?;
+
+        Ok(current_backup)
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+}
+
+fn jar_name(jar: &Path) -> String {
+    jar.file_name()
+        .map_or_else(|| "archive".to_string(), |file_name| file_name.to_string_lossy().into_owned())
+}
+
+/// Hash of the canonical path of the JAR, or of its absolute path when it cannot be resolved.
+/// Only the directory is canonicalized, so the key stays the same once the JAR is deleted.
+fn jar_key(jar: &Path) -> String {
+    let path = fs::canonicalize(parent_directory(jar))
+        .map(|directory| directory.join(jar.file_name().unwrap_or_default()))
+        .or_else(|_| std::path::absolute(jar))
+        .unwrap_or_else(|_| jar.to_path_buf());
+    let hash = format!("{:x}", Sha256::digest(path.to_string_lossy().as_bytes()));
+    hash[..JAR_KEY_LENGTH].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn write_jar(jar: &Path, contents: &str) {
+        fs::create_dir_all(parent_directory(jar)).unwrap();
+        fs::write(jar, contents).unwrap();
+    }
+
+    #[test]
+    fn restore_deleted_jar() {
+        let directory = TempDir::new().unwrap();
+        let jar = directory.path().join("app.jar");
+        write_jar(&jar, "version 1");
+        Backups::new(&jar, None, DEFAULT_BACKUP_KEEP).create().unwrap().unwrap();
+        fs::remove_file(&jar).unwrap();
+
+        let backups = Backups::new(&jar, None, DEFAULT_BACKUP_KEEP);
+        let backup = backups.find("1").unwrap();
+        assert_eq!(backups.restore(&backup).unwrap(), None);
+        assert_eq!(fs::read_to_string(&jar).unwrap(), "version 1");
+        assert_eq!(backups.list().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn restore_backs_up_current_jar() {
+        let directory = TempDir::new().unwrap();
+        let jar = directory.path().join("app.jar");
+        write_jar(&jar, "version 1");
+        let backups = Backups::new(&jar, None, DEFAULT_BACKUP_KEEP);
+        backups.create().unwrap();
+        write_jar(&jar, "version 2");
+
+        let backup = backups.find("1").unwrap();
+        let current_backup = backups.restore(&backup).unwrap().unwrap();
+        assert_eq!(fs::read_to_string(&jar).unwrap(), "version 1");
+        assert_eq!(fs::read_to_string(current_backup).unwrap(), "version 2");
+    }
+
+    #[test]
+    fn jars_of_the_same_name_keep_their_own_backups() {
+        let directory = TempDir::new().unwrap();
+        let backup_directory = directory.path().join("backups");
+        let (first_jar, second_jar) = (directory.path().join("a/app.jar"), directory.path().join("b/app.jar"));
+        write_jar(&first_jar, "first");
+        write_jar(&second_jar, "second");
+
+        let first_backups = Backups::new(&first_jar, Some(&backup_directory), 1);
+        let second_backups = Backups::new(&second_jar, Some(&backup_directory), 1);
+        first_backups.create().unwrap();
+        second_backups.create().unwrap();
+        // Pruning to one backup must leave the other JAR's backup alone
+        second_backups.create().unwrap();
+
+        let first_list = first_backups.list().unwrap();
+        assert_eq!(first_list.len(), 1);
+        assert_eq!(fs::read_to_string(&first_list[0].path).unwrap(), "first");
+        assert_eq!(second_backups.list().unwrap().len(), 1);
+        assert_eq!(fs::read_dir(&backup_directory).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn find_unknown_backup() {
+        let directory = TempDir::new().unwrap();
+        let jar = directory.path().join("app.jar");
+        write_jar(&jar, "version 1");
+        let backups = Backups::new(&jar, None, DEFAULT_BACKUP_KEEP);
+        assert!(matches!(backups.find("1"), Err(Error::BackupNotFound { .. })));
+    }
+}
@@ -0,0 +1,166 @@
+use clap::ArgEnum;
+use serde::Serialize;
+use sicas_audit::Error;
+use std::fmt;
+use std::io::{self, Write};
+use std::process::ExitCode;
+
+/// Exit codes, listed in the help text
+pub const EXIT_CODES_HELP: &str = "EXIT CODES:
+    0   Success
+    1   Unexpected error
+    2   Invalid command line
+    3   Invalid configuration, or the log file cannot be opened
+    4   JAR file not found, or no JARs found in a directory or matching a pattern
+    5   JAR file corrupt, or using ZIP features that cannot be rewritten
+    6   Audit trail file not found in the JAR
+    7   Entry, pattern or backup not found
+    8   Entry or extracted file already exists, or is part of the JAR signature
+    9   Invalid entry name, pattern or audit entry
+    10  Audit trail is not text, has malformed lines or a broken hash chain
+    11  Reading or writing a file failed
+
+When Show, List or Verify fail for some of several JARs, the others are still processed and
+the exit code is that of the first JAR that failed.
+
+With --error-format json every error is printed to stderr as a single line:
+    {\"error\": \"jar_not_found\", \"exit_code\": 4, \"message\": \"...\", \"hint\": null}
+Errors of one of several JARs also have a \"jar\" field. Errors in the command line itself are
+always reported as text.";
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorFormat {
+    Text,
+    Json,
+}
+
+/// Category of a failure, which decides the exit code of the process
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    Unexpected = 1,
+    Usage = 2,
+    Config = 3,
+    JarNotFound = 4,
+    JarCorrupt = 5,
+    AuditFileNotFound = 6,
+    NotFound = 7,
+    Conflict = 8,
+    InvalidInput = 9,
+    InvalidAuditTrail = 10,
+    Io = 11,
+}
+
+/// A failure of a command
+#[derive(Debug)]
+pub enum CliError {
+    /// Arguments that are valid on their own but not together, or missing values
+    Usage(String),
+    Config(anyhow::Error),
+    /// An error of the library, with the option resolving it, if any
+    Archive { error: Error, hint: Option<&'static str> },
+    /// The audit trail was read but does not hold up
+    AuditTrail(String),
+    /// A directory or pattern given with `--jar` matches no JARs
+    NoJarsFound(String),
+    /// Some of several JARs failed, each already reported. `kind` is that of the first failure.
+    JarsFailed { kind: ErrorKind, failed: usize, total: usize },
+    Other(anyhow::Error),
+}
+
+#[derive(Serialize)]
+pub struct ErrorReport<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    jar: Option<&'a str>,
+    error: ErrorKind,
+    exit_code: u8,
+    message: String,
+    hint: Option<&'a str>,
+}
+
+impl CliError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            CliError::Usage(_) => ErrorKind::Usage,
+            CliError::Config(_) => ErrorKind::Config,
+            CliError::AuditTrail(_) => ErrorKind::InvalidAuditTrail,
+            CliError::Other(_) => ErrorKind::Unexpected,
+            CliError::NoJarsFound(_) => ErrorKind::JarNotFound,
+            CliError::JarsFailed { kind, .. } => *kind,
+            CliError::Archive { error, .. } => match error {
+                Error::JarNotFound(_) => ErrorKind::JarNotFound,
+                Error::CorruptArchive { .. } | Error::Unsupported(_) => ErrorKind::JarCorrupt,
+                Error::AuditFileNotFound { .. } => ErrorKind::AuditFileNotFound,
+                Error::EntryNotFound { .. } | Error::BackupNotFound { .. } => ErrorKind::NotFound,
+                Error::EntryExists { .. } | Error::SignatureFile { .. } => ErrorKind::Conflict,
+                Error::InvalidPath { .. } | Error::InvalidPattern { .. } | Error::InvalidAuditEntry(_) => ErrorKind::InvalidInput,
+                Error::NotText(_) => ErrorKind::InvalidAuditTrail,
+                Error::Io { source, .. } if source.kind() == io::ErrorKind::AlreadyExists => ErrorKind::Conflict,
+                Error::Io { .. } => ErrorKind::Io,
+            },
+        }
+    }
+
+    /// Print the error to stderr and return the exit code of its kind
+    pub fn report(&self, format: ErrorFormat) -> ExitCode {
+        self.report_for(None, format);
+        ExitCode::from(self.kind() as u8)
+    }
+
+    /// Print the error to stderr, naming the JAR it occurred in when there are several
+    pub fn report_for(&self, jar: Option<&str>, format: ErrorFormat) {
+        let stderr = io::stderr();
+        let mut stderr = stderr.lock();
+
+        // Nothing is left to report a failure to print the error to
+        let _ = match (format, jar) {
+            (ErrorFormat::Text, Some(jar)) => writeln!(stderr, "Error: {}: {}", jar, self),
+            (ErrorFormat::Text, None) => writeln!(stderr, "Error: {}", self),
+            (ErrorFormat::Json, _) => serde_json::to_writer(&mut stderr, &self.to_report(jar)).map_err(io::Error::from)
+                .and_then(|()| writeln!(stderr)),
+        };
+    }
+
+    pub fn to_report<'a>(&'a self, jar: Option<&'a str>) -> ErrorReport<'a> {
+        let kind = self.kind();
+        let (message, hint) = match self {
+            CliError::Archive { error, hint } => (error.to_string(), *hint),
+            error => (error.to_string(), None),
+        };
+        ErrorReport { jar, error: kind, exit_code: kind as u8, message, hint }
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CliError::Usage(message) | CliError::AuditTrail(message) | CliError::NoJarsFound(message) => f.write_str(message),
+            CliError::JarsFailed { failed, total, .. } => write!(f, "{} of {} JARs failed", failed, total),
+            CliError::Config(error) | CliError::Other(error) => write!(f, "{:#}", error),
+            CliError::Archive { error, hint: Some(hint) } => write!(f, "{}, {}", error, hint),
+            CliError::Archive { error, hint: None } => write!(f, "{}", error),
+        }
+    }
+}
+
+impl From<Error> for CliError {
+    fn from(error: Error) -> CliError {
+        CliError::Archive { error, hint: None }
+    }
+}
+
+impl From<io::Error> for CliError {
+    fn from(error: io::Error) -> CliError {
+        Error::from(error).into()
+    }
+}
+
+/// Library errors passed through anyhow keep their kind
+impl From<anyhow::Error> for CliError {
+    fn from(error: anyhow::Error) -> CliError {
+        match error.downcast::<Error>() {
+            Ok(error) => error.into(),
+            Err(error) => CliError::Other(error),
+        }
+    }
+}
@@ -0,0 +1,525 @@
+use anyhow::{anyhow, Result};
+use configparser::ini::Ini;
+use globset::Glob;
+use log::LevelFilter;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use sicas_audit::archive::{CompressionMethod, EntryCompression};
+use sicas_audit::patterns::parse_pattern_list;
+
+use crate::logging::{parse_size, Rotation};
+
+/// Environment variables overriding a key are named after it, e.g. `SICAS_AUDIT_LOG_LEVEL`
+const ENV_PREFIX: &str = "SICAS_AUDIT_";
+
+/// Sections named `[profile.<name>]` hold the values of a named profile
+const PROFILE_PREFIX: &str = "profile.";
+
+/// Profile key selecting the profile automatically for JAR files whose name matches this glob
+const JAR_PATTERN_KEY: &str = "JAR_PATTERN";
+
+/// Every key sicas_audit understands, with its built-in default and validation
+const KEYS: [KeySpec; 12] = [
+    KeySpec { section: "LOGGING", key: "LOG_LEVEL", default: Some("info"), validate: validate_level },
+    KeySpec { section: "LOGGING", key: "LOG_FILE", default: None, validate: validate_any },
+    KeySpec { section: "LOGGING", key: "LOG_FILE_LEVEL", default: None, validate: validate_level },
+    KeySpec { section: "LOGGING", key: "LOG_ROTATION", default: Some("size"), validate: validate_rotation },
+    KeySpec { section: "LOGGING", key: "LOG_MAX_SIZE", default: Some("10M"), validate: validate_size },
+    KeySpec { section: "LOGGING", key: "LOG_KEEP", default: Some("5"), validate: validate_count },
+    KeySpec { section: "AUDIT", key: "AUDIT_FILE", default: Some("AUDIT_TRAIL"), validate: validate_not_empty },
+    KeySpec { section: "AUDIT", key: "IGNORED_FILES", default: Some(""), validate: validate_patterns },
+    KeySpec { section: "ARCHIVE", key: "COMPRESSION", default: Some("deflated"), validate: validate_compression },
+    KeySpec { section: "ARCHIVE", key: "COMPRESSION_LEVEL", default: Some("6"), validate: validate_compression_level },
+    KeySpec { section: "BACKUP", key: "BACKUP_DIR", default: None, validate: validate_any },
+    KeySpec { section: "BACKUP", key: "BACKUP_KEEP", default: Some("10"), validate: validate_count },
+];
+
+struct KeySpec {
+    section: &'static str,
+    key: &'static str,
+    default: Option<&'static str>,
+    validate: fn(&str) -> Result<(), String>,
+}
+
+/// Effective configuration. Each layer overrides the ones before it: the built-in defaults,
+/// the system file, the user file, the project file, the selected profile,
+/// `SICAS_AUDIT_*` environment variables and finally command line flags.
+pub struct Config {
+    /// Name of the selected profile, if any
+    pub profile: Option<String>,
+    pub logging: LoggingConfig,
+    pub audit: AuditConfig,
+    pub archive: ArchiveConfig,
+    pub backup: BackupConfig,
+    /// Problems that do not prevent running, such as unknown keys
+    pub warnings: Vec<ConfigProblem>,
+    values: RawValues,
+}
+
+pub struct LoggingConfig {
+    pub level: LevelFilter,
+    /// Relative paths are resolved against the directory of the file setting them
+    pub file: Option<PathBuf>,
+    pub file_level: LevelFilter,
+    pub rotation: Rotation,
+    /// Number of rotated log files to keep
+    pub keep: usize,
+}
+
+pub struct AuditConfig {
+    pub audit_file: String,
+    pub ignored_files: Vec<String>,
+}
+
+pub struct ArchiveConfig {
+    /// Compression of entries added with Add
+    pub compression: EntryCompression,
+}
+
+pub struct BackupConfig {
+    /// Defaults to a directory next to the JAR. Relative paths are resolved against the
+    /// directory of the file setting them
+    pub directory: Option<PathBuf>,
+    /// Number of backups kept per JAR, 0 disables backups
+    pub keep: usize,
+}
+
+/// Values passed on the command line, the last configuration layer
+#[derive(Default)]
+pub struct CliOverrides {
+    pub audit_file: Option<String>,
+    /// Profile selected with `--profile`
+    pub profile: Option<String>,
+    /// JAR file the profile is matched against when none is selected
+    pub jar: Option<String>,
+}
+
+/// A problem found while validating a configuration layer
+pub struct ConfigProblem {
+    pub source: ValueSource,
+    pub section: String,
+    pub key: String,
+    pub message: String,
+}
+
+#[derive(Clone)]
+pub enum ValueSource {
+    Default,
+    File { path: PathBuf, line: Option<usize> },
+    Environment(String),
+    CommandLine(&'static str),
+}
+
+/// Raw values of the known keys, each with the layer that set it last
+#[derive(Default)]
+struct RawValues {
+    values: HashMap<(&'static str, &'static str), (String, ValueSource)>,
+    /// Values of every profile by lowercase name, in the order they were read
+    profiles: BTreeMap<String, Vec<ProfileValue>>,
+    errors: Vec<ConfigProblem>,
+    warnings: Vec<ConfigProblem>,
+}
+
+struct ProfileValue {
+    key: String,
+    value: String,
+    source: ValueSource,
+}
+
+impl Config {
+    /// Load and validate every configuration layer. Missing files are skipped, invalid values
+    /// in any layer fail with every problem found.
+    pub fn load(project_file: &Path, cli_overrides: &CliOverrides) -> Result<Config> {
+        let mut values = RawValues::defaults();
+
+        for path in system_config_file().into_iter().chain(user_config_file()) {
+            values.overlay_file(&path)?;
+        }
+        values.overlay_file(project_file)?;
+        let profile = values.select_profile(cli_overrides)?;
+        if let Some(profile) = &profile {
+            values.overlay_profile(profile);
+        }
+        values.overlay_env();
+        if let Some(audit_file) = &cli_overrides.audit_file {
+            values.set("AUDIT", "AUDIT_FILE", audit_file, ValueSource::CommandLine("--file"));
+        }
+
+        if !values.errors.is_empty() {
+            let problems = values.errors.iter()
+                .map(|problem| format!("  {}", problem))
+                .collect::<Vec<String>>()
+                .join("\n");
+            return Err(anyhow!("Invalid configuration, {} problem(s) found:\n{}", values.errors.len(), problems));
+        }
+
+        let level = values.level("LOGGING", "LOG_LEVEL")?.unwrap_or(LevelFilter::Info);
+        let logging = LoggingConfig {
+            level,
+            file: values.path("LOGGING", "LOG_FILE"),
+            file_level: values.level("LOGGING", "LOG_FILE_LEVEL")?.unwrap_or(level),
+            rotation: match values.get("LOGGING", "LOG_ROTATION").to_ascii_lowercase().as_str() {
+                "daily" => Rotation::Daily,
+                "never" => Rotation::Never,
+                _ => Rotation::Size(parse_size(values.get("LOGGING", "LOG_MAX_SIZE"))?),
+            },
+            keep: values.get("LOGGING", "LOG_KEEP").parse()?,
+        };
+        let ignored_files = parse_pattern_list(values.get("AUDIT", "IGNORED_FILES"))?;
+        // Warned about once the logger is set up, like unknown keys
+        for warning in ignored_files.warnings {
+            values.warnings.push(ConfigProblem {
+                source: values.source("AUDIT", "IGNORED_FILES"),
+                section: "AUDIT".to_string(),
+                key: "IGNORED_FILES".to_string(),
+                message: warning,
+            });
+        }
+        let audit = AuditConfig {
+            audit_file: values.get("AUDIT", "AUDIT_FILE").to_string(),
+            ignored_files: ignored_files.patterns,
+        };
+
+        let archive = ArchiveConfig {
+            compression: EntryCompression {
+                method: parse_compression(values.get("ARCHIVE", "COMPRESSION")).map_err(|err| anyhow!(err))?,
+                level: parse_compression_level(values.get("ARCHIVE", "COMPRESSION_LEVEL")).map_err(|err| anyhow!(err))?,
+            },
+        };
+
+        let backup = BackupConfig {
+            directory: values.path("BACKUP", "BACKUP_DIR"),
+            keep: values.get("BACKUP", "BACKUP_KEEP").parse()?,
+        };
+
+        Ok(Config { profile, logging, audit, archive, backup, warnings: std::mem::take(&mut values.warnings), values })
+    }
+
+    /// Render the effective configuration as INI, noting where every value comes from
+    pub fn to_ini_string(&self) -> String {
+        let mut ini = String::new();
+        let mut current_section = None;
+
+        if let Some(profile) = &self.profile {
+            ini.push_str(&format!("; profile {}\n", profile));
+        }
+
+        for spec in &KEYS {
+            let (value, source) = match self.values.values.get(&(spec.section, spec.key)) {
+                Some(config_value) => config_value,
+                None => continue,
+            };
+
+            if current_section != Some(spec.section) {
+                if current_section.is_some() {
+                    ini.push('\n');
+                }
+                ini.push_str(&format!("[{}]\n", spec.section));
+                current_section = Some(spec.section);
+            }
+
+            let value = value.lines().collect::<Vec<&str>>().join("\n    ");
+            ini.push_str(&format!("{} = {}    ; {}\n", spec.key, value, source));
+        }
+
+        ini
+    }
+}
+
+impl RawValues {
+    fn defaults() -> RawValues {
+        let values = KEYS.iter()
+            .filter_map(|spec| spec.default.map(|default| ((spec.section, spec.key), (default.to_string(), ValueSource::Default))))
+            .collect();
+
+        RawValues { values, ..RawValues::default() }
+    }
+
+    /// Overlay the values of a configuration file, skipping it when it does not exist
+    fn overlay_file(&mut self, path: &Path) -> Result<()> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(anyhow!("Unable to read configuration {:?}: {}", path, err)),
+        };
+
+        let mut ini = Ini::new();
+        ini.set_multiline(true);
+        ini.read(contents.clone())
+            .map_err(|err| anyhow!("Unable to parse configuration {:?}: {}", path, err))?;
+        let key_lines = find_key_lines(&contents);
+
+        let mut file_values = ini.get_map_ref().iter()
+            .flat_map(|(section, keys)| keys.iter().map(move |(key, value)| (section, key, value)))
+            .map(|(section, key, value)| {
+                let line = key_lines.get(&(section.clone(), key.clone())).copied();
+                (section.to_ascii_uppercase(), key.to_ascii_uppercase(), value.clone().unwrap_or_default(), line)
+            })
+            .collect::<Vec<(String, String, String, Option<usize>)>>();
+        file_values.sort_by_key(|(_, _, _, line)| *line);
+
+        for (section, key, value, line) in file_values {
+            let source = ValueSource::File { path: path.to_path_buf(), line };
+            if let Some(profile) = section.to_ascii_lowercase().strip_prefix(PROFILE_PREFIX) {
+                self.add_profile_value(profile, &key, &value, source);
+            } else if !self.set(&section, &key, &value, source.clone()) {
+                self.warnings.push(ConfigProblem { source, section, key, message: "unknown key, it is ignored".to_string() });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn add_profile_value(&mut self, profile: &str, key: &str, value: &str, source: ValueSource) {
+        if key == JAR_PATTERN_KEY {
+            if let Err(err) = Glob::new(value.trim()) {
+                self.errors.push(ConfigProblem {
+                    source,
+                    section: format!("{}{}", PROFILE_PREFIX, profile),
+                    key: key.to_string(),
+                    message: format!("invalid pattern {:?}: {}", value.trim(), err.kind()),
+                });
+                return;
+            }
+        }
+
+        self.profiles.entry(profile.to_string()).or_default().push(ProfileValue {
+            key: key.to_string(),
+            value: value.to_string(),
+            source,
+        });
+    }
+
+    /// The profile selected on the command line, or else the one whose `JAR_PATTERN` matches
+    /// the name of the JAR file
+    fn select_profile(&self, cli_overrides: &CliOverrides) -> Result<Option<String>> {
+        if let Some(profile) = &cli_overrides.profile {
+            let profile = profile.to_ascii_lowercase();
+            if self.profiles.contains_key(&profile) {
+                return Ok(Some(profile));
+            }
+            return Err(match self.profiles.is_empty() {
+                true => anyhow!("Unknown profile {:?}, no profiles are configured", profile),
+                false => anyhow!("Unknown profile {:?}, expected one of {}", profile,
+                    self.profiles.keys().cloned().collect::<Vec<String>>().join(", ")),
+            });
+        }
+
+        let jar_name = match cli_overrides.jar.as_deref().and_then(|jar| Path::new(jar).file_name()) {
+            Some(jar_name) => jar_name,
+            None => return Ok(None),
+        };
+        let matching_profiles = self.profiles.iter()
+            .filter(|(_, profile_values)| profile_values.iter()
+                .rfind(|profile_value| profile_value.key == JAR_PATTERN_KEY)
+                .and_then(|profile_value| Glob::new(profile_value.value.trim()).ok())
+                .is_some_and(|glob| glob.compile_matcher().is_match(jar_name)))
+            .map(|(profile, _)| profile.clone())
+            .collect::<Vec<String>>();
+
+        match matching_profiles.as_slice() {
+            [] => Ok(None),
+            [profile] => Ok(Some(profile.clone())),
+            profiles => Err(anyhow!("{:?} matches profiles {}, select one with --profile",
+                jar_name, profiles.join(", "))),
+        }
+    }
+
+    fn overlay_profile(&mut self, profile: &str) {
+        let profile_values = self.profiles.remove(profile).unwrap_or_default();
+        for ProfileValue { key, value, source } in profile_values {
+            if key == JAR_PATTERN_KEY {
+                continue;
+            }
+
+            let spec = KEYS.iter().find(|spec| spec.key == key);
+            if !spec.is_some_and(|spec| self.set(spec.section, spec.key, &value, source.clone())) {
+                self.warnings.push(ConfigProblem {
+                    source,
+                    section: format!("{}{}", PROFILE_PREFIX, profile),
+                    key,
+                    message: "unknown key, it is ignored".to_string(),
+                });
+            }
+        }
+    }
+
+    fn overlay_env(&mut self) {
+        for spec in &KEYS {
+            let variable = format!("{}{}", ENV_PREFIX, spec.key);
+            if let Ok(value) = env::var(&variable) {
+                self.set(spec.section, spec.key, &value, ValueSource::Environment(variable));
+            }
+        }
+    }
+
+    /// Validate and store a value. Returns false when the key is unknown.
+    fn set(&mut self, section: &str, key: &str, value: &str, source: ValueSource) -> bool {
+        let spec = match KEYS.iter().find(|spec| spec.section == section && spec.key == key) {
+            Some(spec) => spec,
+            None => return false,
+        };
+
+        let value = value.trim();
+        match (spec.validate)(value) {
+            Ok(()) => {
+                self.values.insert((spec.section, spec.key), (value.to_string(), source));
+            }
+            Err(message) => self.errors.push(ConfigProblem {
+                source,
+                section: spec.section.to_string(),
+                key: spec.key.to_string(),
+                message,
+            }),
+        }
+        true
+    }
+
+    fn get(&self, section: &'static str, key: &'static str) -> &str {
+        self.values.get(&(section, key)).map_or("", |(value, _)| value.as_str())
+    }
+
+    fn source(&self, section: &'static str, key: &'static str) -> ValueSource {
+        self.values.get(&(section, key)).map_or(ValueSource::Default, |(_, source)| source.clone())
+    }
+
+    fn level(&self, section: &'static str, key: &'static str) -> Result<Option<LevelFilter>> {
+        match self.values.get(&(section, key)) {
+            Some((value, _)) => Ok(Some(parse_level(value).map_err(|err| anyhow!(err))?)),
+            None => Ok(None),
+        }
+    }
+
+    /// A path value, resolved against the directory of the file that set it
+    fn path(&self, section: &'static str, key: &'static str) -> Option<PathBuf> {
+        let (value, source) = self.values.get(&(section, key)).filter(|(value, _)| !value.is_empty())?;
+        match source {
+            ValueSource::File { path, .. } => Some(path.parent().unwrap_or_else(|| Path::new("")).join(value)),
+            _ => Some(PathBuf::from(value)),
+        }
+    }
+}
+
+/// Configuration shared by every user of the machine
+fn system_config_file() -> Option<PathBuf> {
+    if cfg!(windows) {
+        env::var_os("PROGRAMDATA").map(|program_data| PathBuf::from(program_data).join("sicas_audit").join("config.ini"))
+    } else {
+        Some(PathBuf::from("/etc/sicas_audit/config.ini"))
+    }
+}
+
+/// Configuration of the current user, `$XDG_CONFIG_HOME/sicas_audit/config.ini` on Linux
+fn user_config_file() -> Option<PathBuf> {
+    dirs::config_dir().map(|config_dir| config_dir.join("sicas_audit").join("config.ini"))
+}
+
+impl fmt::Display for ValueSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValueSource::Default => f.write_str("default"),
+            ValueSource::File { path, line: Some(line) } => write!(f, "{}:{}", path.display(), line),
+            ValueSource::File { path, line: None } => write!(f, "{}", path.display()),
+            ValueSource::Environment(variable) => write!(f, "${}", variable),
+            ValueSource::CommandLine(flag) => f.write_str(flag),
+        }
+    }
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: [{}] {}: {}", self.source, self.section, self.key, self.message)
+    }
+}
+
+/// Line numbers of every `key = value` line, keyed by lowercase section and key like [`Ini`]
+fn find_key_lines(contents: &str) -> HashMap<(String, String), usize> {
+    let mut key_lines = HashMap::new();
+    let mut section = "default".to_string();
+
+    for (index, line) in contents.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            if let Some(end) = trimmed.find(']') {
+                section = trimmed[1..end].trim().to_ascii_lowercase();
+            }
+        } else if !line.starts_with(char::is_whitespace) {
+            if let Some((key, _)) = trimmed.split_once(['=', ':']) {
+                key_lines.insert((section.clone(), key.trim().to_ascii_lowercase()), index + 1);
+            }
+        }
+    }
+
+    key_lines
+}
+
+pub fn parse_level(level: &str) -> Result<LevelFilter, String> {
+    LevelFilter::from_str(level)
+        .map_err(|_| format!("invalid level {:?}, expected trace, debug, info, warn, error or off", level))
+}
+
+pub fn parse_compression(method: &str) -> Result<CompressionMethod, String> {
+    match method.to_ascii_lowercase().as_str() {
+        "stored" => Ok(CompressionMethod::Stored),
+        "deflated" => Ok(CompressionMethod::Deflated),
+        _ => Err(format!("invalid compression {:?}, expected stored or deflated", method)),
+    }
+}
+
+pub fn parse_compression_level(level: &str) -> Result<u32, String> {
+    level.parse::<u32>()
+        .ok()
+        .filter(|level| *level <= 9)
+        .ok_or_else(|| format!("invalid compression level {:?}, expected 0 (fastest) to 9 (smallest)", level))
+}
+
+fn validate_level(value: &str) -> Result<(), String> {
+    parse_level(value).map(|_| ())
+}
+
+fn validate_rotation(value: &str) -> Result<(), String> {
+    match value.to_ascii_lowercase().as_str() {
+        "size" | "daily" | "never" => Ok(()),
+        _ => Err(format!("invalid rotation {:?}, expected size, daily or never", value)),
+    }
+}
+
+fn validate_size(value: &str) -> Result<(), String> {
+    parse_size(value).map(|_| ()).map_err(|err| err.to_string())
+}
+
+fn validate_count(value: &str) -> Result<(), String> {
+    value.parse::<usize>()
+        .map(|_| ())
+        .map_err(|_| format!("invalid number {:?}, expected a whole number of 0 or more", value))
+}
+
+fn validate_not_empty(value: &str) -> Result<(), String> {
+    if value.is_empty() {
+        Err("value cannot be empty".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+fn validate_patterns(value: &str) -> Result<(), String> {
+    parse_pattern_list(value).map(|_| ()).map_err(|err| err.to_string())
+}
+
+fn validate_compression(value: &str) -> Result<(), String> {
+    parse_compression(value).map(|_| ())
+}
+
+fn validate_compression_level(value: &str) -> Result<(), String> {
+    parse_compression_level(value).map(|_| ())
+}
+
+fn validate_any(_value: &str) -> Result<(), String> {
+    Ok(())
+}
@@ -0,0 +1,79 @@
+use anyhow::Result;
+use sicas_audit::archive::{open_archive, ArchiveRewrite, EntryChange};
+use sicas_audit::encoding::{decode, Contents};
+use similar::TextDiff;
+use std::io::Read;
+
+/// Print what a rewrite would change in the JAR instead of writing it: every added, removed,
+/// modified and renamed entry with its size delta, followed by a unified diff of the text entries
+pub fn print_changes(jar: &str, rewrite: &ArchiveRewrite) -> Result<()> {
+    let mut archive = open_archive(jar)?;
+    let mut read_original = |name: &str| -> Result<Vec<u8>> {
+        let mut contents = Vec::new();
+        archive.by_name(name)?.read_to_end(&mut contents)?;
+        Ok(contents)
+    };
+
+    let mut lines = Vec::new();
+    let mut diffs = Vec::new();
+    let (mut added, mut removed, mut modified, mut renamed, mut size_delta) = (0, 0, 0, 0, 0i64);
+    for change in rewrite.changes() {
+        let (status, label, delta) = match change {
+            EntryChange::Added { name, contents } => {
+                added += 1;
+                diffs.push(unified_diff(name, &[], contents));
+                ("A", name.to_string(), contents.len() as i64)
+            }
+            EntryChange::Removed { name } => {
+                removed += 1;
+                ("D", name.to_string(), -(read_original(name)?.len() as i64))
+            }
+            EntryChange::Modified { original_name, name, contents } => {
+                modified += 1;
+                let original_contents = read_original(original_name)?;
+                diffs.push(unified_diff(name, &original_contents, contents));
+                ("M", rename_label(original_name, name), contents.len() as i64 - original_contents.len() as i64)
+            }
+            EntryChange::Renamed { original_name, name } => {
+                renamed += 1;
+                ("R", rename_label(original_name, name), 0)
+            }
+        };
+        size_delta += delta;
+        lines.push((status, label, delta));
+    }
+
+    println!("Dry run, {} was not modified", jar);
+    let label_width = lines.iter().map(|(_, label, _)| label.chars().count()).max().unwrap_or(0);
+    for (status, label, delta) in &lines {
+        println!("{}  {:<label_width$}  {:+} bytes", status, label, delta, label_width = label_width);
+    }
+    println!("{} added, {} removed, {} modified, {} renamed, {:+} bytes", added, removed, modified, renamed, size_delta);
+
+    for diff in diffs.iter().flatten() {
+        print!("\n{}", diff);
+    }
+    Ok(())
+}
+
+fn rename_label(original_name: &str, name: &str) -> String {
+    match original_name == name {
+        true => name.to_string(),
+        false => format!("{} -> {}", original_name, name),
+    }
+}
+
+/// Unified diff of an entry, or a note when either side is not text. `None` when nothing changed.
+fn unified_diff(name: &str, original: &[u8], contents: &[u8]) -> Option<String> {
+    if original == contents {
+        return None;
+    }
+
+    match (decode(original, None), decode(contents, None)) {
+        (Contents::Text { text: original, .. }, Contents::Text { text: contents, .. }) => Some(TextDiff::from_lines(&original, &contents)
+            .unified_diff()
+            .header(&format!("a/{}", name), &format!("b/{}", name))
+            .to_string()),
+        _ => Some(format!("Binary contents of {} differ\n", name)),
+    }
+}
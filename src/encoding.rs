@@ -0,0 +1,114 @@
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8, WINDOWS_1252};
+use std::io::{self, Write};
+
+/// Number of bytes inspected when guessing whether contents are text
+const SNIFF_LENGTH: usize = 8192;
+
+/// Contents of an archive entry, decoded when they are text
+pub enum Contents {
+    Text {
+        text: String,
+        encoding: &'static Encoding,
+        /// Whether some bytes were not valid in the encoding and got replaced
+        had_errors: bool,
+    },
+    Binary,
+}
+
+/// Parse an encoding name such as `utf-8`, `utf-16le`, `latin1` or `windows-1252`
+pub fn parse_encoding(label: &str) -> Result<&'static Encoding, String> {
+    Encoding::for_label(label.trim().as_bytes())
+        .ok_or_else(|| format!("Unknown encoding {:?}, expected e.g. utf-8, utf-16le, utf-16be or latin1", label))
+}
+
+/// Decode `bytes` with the given encoding, or else with the one detected from a byte order
+/// mark or the contents. Undecodable contents without an encoding are reported as binary.
+pub fn decode(bytes: &[u8], encoding: Option<&'static Encoding>) -> Contents {
+    let encoding = match encoding.or_else(|| detect_encoding(bytes)) {
+        Some(encoding) => encoding,
+        None => return Contents::Binary,
+    };
+
+    let (text, had_errors) = encoding.decode_with_bom_removal(bytes);
+    Contents::Text { text: text.into_owned(), encoding, had_errors }
+}
+
+/// Encode `text` to write it back in the encoding it was read with. `None` when the encoding
+/// cannot represent some of its characters, which encoding_rs would replace with HTML entities.
+pub fn encode(text: &str, encoding: &'static Encoding) -> Option<Vec<u8>> {
+    // encoding_rs decodes UTF-16 but only encodes to UTF-8
+    if encoding == UTF_16LE {
+        Some(text.encode_utf16().flat_map(u16::to_le_bytes).collect())
+    } else if encoding == UTF_16BE {
+        Some(text.encode_utf16().flat_map(u16::to_be_bytes).collect())
+    } else {
+        match encoding.encode(text) {
+            (_, _, true) => None,
+            (bytes, _, false) => Some(bytes.into_owned()),
+        }
+    }
+}
+
+fn detect_encoding(bytes: &[u8]) -> Option<&'static Encoding> {
+    if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+        return Some(encoding);
+    }
+
+    let sample = &bytes[..bytes.len().min(SNIFF_LENGTH)];
+    if let Some(encoding) = detect_utf16(sample) {
+        return Some(encoding);
+    }
+    if sample.iter().any(|&byte| is_binary_control(byte)) {
+        return None;
+    }
+    if std::str::from_utf8(bytes).is_ok() {
+        return Some(UTF_8);
+    }
+
+    // Older Windows tooling writes Latin-1, which windows-1252 extends
+    Some(WINDOWS_1252)
+}
+
+/// Recognize UTF-16 without a byte order mark by mostly ASCII text having a zero in every
+/// other byte
+fn detect_utf16(sample: &[u8]) -> Option<&'static Encoding> {
+    if sample.len() < 2 || !sample.len().is_multiple_of(2) {
+        return None;
+    }
+
+    let pairs = sample.len() / 2;
+    let even_zeros = sample.iter().step_by(2).filter(|&&byte| byte == 0).count();
+    let odd_zeros = sample.iter().skip(1).step_by(2).filter(|&&byte| byte == 0).count();
+    if odd_zeros * 10 >= pairs * 9 && even_zeros == 0 {
+        Some(UTF_16LE)
+    } else if even_zeros * 10 >= pairs * 9 && odd_zeros == 0 {
+        Some(UTF_16BE)
+    } else {
+        None
+    }
+}
+
+/// Control characters that do not appear in text files, unlike tabs, line breaks or form feeds
+fn is_binary_control(byte: u8) -> bool {
+    matches!(byte, 0x00..=0x08 | 0x0e..=0x1a | 0x1c..=0x1f)
+}
+
+/// Write `bytes` in the format of `hexdump -C`: offset, 16 bytes in hex and their printable
+/// characters
+pub fn write_hexdump<W: Write>(writer: &mut W, bytes: &[u8]) -> io::Result<()> {
+    for (index, line) in bytes.chunks(16).enumerate() {
+        let mut hex = String::new();
+        for (position, byte) in line.iter().enumerate() {
+            if position == 8 {
+                hex.push(' ');
+            }
+            hex.push_str(&format!("{:02x} ", byte));
+        }
+        let printable = line.iter()
+            .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+            .collect::<String>();
+
+        writeln!(writer, "{:08x}  {:<49} |{}|", index * 16, hex, printable)?;
+    }
+    writeln!(writer, "{:08x}", bytes.len())
+}
@@ -0,0 +1,83 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Errors of the operations on a JAR and its audit trail
+#[derive(Debug)]
+pub enum Error {
+    /// The JAR file does not exist
+    JarNotFound(PathBuf),
+    /// The JAR file is not a readable ZIP archive
+    CorruptArchive { jar: PathBuf, reason: String },
+    /// The archive uses a feature that cannot be rewritten, such as ZIP64 or encryption
+    Unsupported(String),
+    EntryNotFound { jar: PathBuf, name: String },
+    /// The JAR has no audit trail under the configured name
+    AuditFileNotFound { jar: PathBuf, name: String },
+    EntryExists { jar: PathBuf, name: String },
+    /// An entry name that cannot be used, such as one that is not a relative path to a file
+    InvalidPath { name: String, reason: String },
+    /// Changing the entry would invalidate the signature of the JAR
+    SignatureFile { jar: PathBuf, name: String },
+    /// The entry is neither text in the requested encoding nor in a detected one
+    NotText(String),
+    InvalidAuditEntry(String),
+    InvalidPattern { pattern: String, reason: String },
+    BackupNotFound { jar: PathBuf, backup: String, directory: PathBuf },
+    /// Reading or writing a file failed. `context` says which and is empty for the unexpected
+    /// failures converted with `?`.
+    Io { context: String, source: io::Error },
+}
+
+impl Error {
+    pub(crate) fn io<S: Into<String>>(context: S, source: io::Error) -> Error {
+        Error::Io { context: context.into(), source }
+    }
+
+    /// Classify an error of the zip crate reading `jar`
+    pub(crate) fn zip<P: Into<PathBuf>>(jar: P, err: zip::result::ZipError) -> Error {
+        let jar = jar.into();
+        match err {
+            zip::result::ZipError::Io(source) => Error::io(format!("Unable to read JAR file {:?}", jar), source),
+            err => Error::CorruptArchive { jar, reason: err.to_string() },
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::JarNotFound(jar) => write!(f, "Unable to open JAR file: {:?}", jar),
+            Error::CorruptArchive { jar, reason } => write!(f, "Unable to read JAR file {:?}: {}", jar, reason),
+            Error::Unsupported(reason) => f.write_str(reason),
+            Error::EntryNotFound { jar, name } => write!(f, "{:?} does not exist in {:?}", name, jar),
+            Error::AuditFileNotFound { jar, name } => write!(f, "Audit trail {:?} does not exist in {:?}", name, jar),
+            Error::EntryExists { jar, name } => write!(f, "{:?} already exists in {:?}", name, jar),
+            Error::InvalidPath { name, reason } => write!(f, "Invalid archive path {:?}, {}", name, reason),
+            Error::SignatureFile { jar, name } => write!(f, "{} is part of the signature of {:?}", name, jar),
+            Error::NotText(name) => write!(f, "{} is not text", name),
+            Error::InvalidAuditEntry(reason) => write!(f, "Unable to create audit entry: {}", reason),
+            Error::InvalidPattern { pattern, reason } => write!(f, "Invalid pattern {:?}: {}", pattern, reason),
+            Error::BackupNotFound { jar, backup, directory } => write!(f, "No backup {:?} of {:?} in {:?}", backup, jar, directory),
+            Error::Io { context, source } if context.is_empty() => write!(f, "{}", source),
+            Error::Io { context, source } => write!(f, "{}: {}", context, source),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(source: io::Error) -> Error {
+        Error::io(String::new(), source)
+    }
+}
@@ -0,0 +1,111 @@
+use globset::GlobBuilder;
+use ignore::WalkBuilder;
+use sicas_audit::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cli_error::CliError;
+
+/// Extensions of the archives found when a directory is given
+const ARCHIVE_EXTENSIONS: [&str; 3] = ["jar", "war", "ear"];
+
+const GLOB_CHARACTERS: [char; 4] = ['*', '?', '[', '{'];
+
+/// Expand the `--jar` values into the JARs to operate on, in order and without duplicates, even
+/// when the same JAR is reached through different paths.
+/// Directories are searched recursively for JAR, WAR and EAR files. Glob patterns such as
+/// `deploy/**/*.jar` are matched against the files below their leading directory. Any other
+/// value is kept as it is, so that a missing JAR is reported when it is opened.
+pub fn resolve_jars(values: &[String]) -> Result<Vec<String>, CliError> {
+    let mut jars = Vec::new();
+    let mut seen = Vec::new();
+    for value in values {
+        let path = Path::new(value);
+        let found = if path.is_dir() {
+            let found = walk_files(path, has_archive_extension)?;
+            if found.is_empty() {
+                return Err(CliError::NoJarsFound(format!("No JAR, WAR or EAR files found in {:?}", value)));
+            }
+            found
+        } else if !path.exists() && value.contains(GLOB_CHARACTERS) {
+            let found = glob_files(value)?;
+            if found.is_empty() {
+                return Err(CliError::NoJarsFound(format!("No files match {:?}", value)));
+            }
+            found
+        } else {
+            vec![display_path(path)]
+        };
+
+        for jar in found {
+            let key = canonical_path(&jar);
+            if !seen.contains(&key) {
+                seen.push(key);
+                jars.push(jar);
+            }
+        }
+    }
+
+    Ok(jars)
+}
+
+fn glob_files(pattern: &str) -> Result<Vec<String>, CliError> {
+    let glob = GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()
+        .map_err(|err| Error::InvalidPattern { pattern: pattern.to_string(), reason: err.kind().to_string() })?
+        .compile_matcher();
+
+    // Only the directory before the first component with a wildcard has to be searched
+    let base = pattern.split('/')
+        .take_while(|component| !component.contains(GLOB_CHARACTERS))
+        .collect::<Vec<&str>>()
+        .join("/");
+    let base = match base.as_str() {
+        "" if pattern.starts_with('/') => "/",
+        "" => ".",
+        base => base,
+    };
+    if !Path::new(base).is_dir() {
+        return Ok(Vec::new());
+    }
+
+    walk_files(Path::new(base), |file| {
+        // Files below the current directory are named like the pattern, without "./"
+        let file = if base == "." { file.strip_prefix(".").unwrap_or(file) } else { file };
+        glob.is_match(file)
+    })
+}
+
+/// Files below `directory` accepted by `filter`, sorted by path. Hidden files and ignore files
+/// are not treated specially, since deployments are not source trees.
+fn walk_files<F>(directory: &Path, filter: F) -> Result<Vec<String>, CliError>
+where
+    F: Fn(&Path) -> bool,
+{
+    let mut files = Vec::new();
+    for entry in WalkBuilder::new(directory).standard_filters(false).build() {
+        let entry = entry.map_err(|err| CliError::Other(anyhow::anyhow!("Unable to search {:?}: {}", directory, err)))?;
+        if entry.file_type().is_some_and(|file_type| file_type.is_file()) && filter(entry.path()) {
+            files.push(entry.into_path());
+        }
+    }
+
+    files.sort();
+    Ok(files.iter().map(|file| display_path(file)).collect())
+}
+
+fn has_archive_extension(file: &Path) -> bool {
+    file.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| ARCHIVE_EXTENSIONS.iter().any(|archive_extension| extension.eq_ignore_ascii_case(archive_extension)))
+}
+
+/// Path identifying a JAR however it was named, or the name itself when it does not exist
+fn canonical_path(jar: &str) -> PathBuf {
+    fs::canonicalize(jar).unwrap_or_else(|_| PathBuf::from(jar))
+}
+
+fn display_path(path: &Path) -> String {
+    path.strip_prefix(".").unwrap_or(path).to_string_lossy().into_owned()
+}
@@ -0,0 +1,17 @@
+//! Reading and changing the audit trail kept inside SICAS JAR files, and the JARs themselves.
+//!
+//! [`AuditArchive`] covers the operations of the `sicas_audit` command: listing and reading
+//! entries, editing, adding, renaming and deleting them, and appending to the hash-chained
+//! audit trail. The modules below it are public for finer control.
+
+pub mod archive;
+pub mod atomic;
+pub mod audit;
+mod audit_archive;
+pub mod backup;
+pub mod encoding;
+mod error;
+pub mod patterns;
+
+pub use audit_archive::{ArchiveFile, AuditArchive, PendingChange, DEFAULT_AUDIT_FILE};
+pub use error::{Error, Result};
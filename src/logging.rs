@@ -0,0 +1,217 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local, NaiveDate, SecondsFormat};
+use log::{LevelFilter, Log, Metadata, Record};
+use simple_logger::SimpleLogger;
+use sicas_audit::atomic::parent_directory;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Log target of the line recording each invocation. It only goes to the log file.
+pub const INVOCATION_TARGET: &str = "sicas_audit::invocation";
+
+/// Dependencies that are too chatty below warnings
+const QUIET_TARGETS: [&str; 1] = ["globset"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    Never,
+    /// Rotate once the file would grow beyond this many bytes
+    Size(u64),
+    /// Rotate on the first write of a new day
+    Daily,
+}
+
+pub struct FileLogConfig {
+    pub path: PathBuf,
+    pub level: LevelFilter,
+    pub rotation: Rotation,
+    /// Number of rotated files to keep
+    pub keep: usize,
+}
+
+/// Logs to the console through [`SimpleLogger`] and, when configured, to a rotating log file.
+/// Both outputs filter on their own level.
+struct Logger {
+    console: SimpleLogger,
+    file: Option<FileLogger>,
+}
+
+struct FileLogger {
+    level: LevelFilter,
+    file: Mutex<RotatingFile>,
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    rotation: Rotation,
+    keep: usize,
+    file: File,
+    size: u64,
+    day: NaiveDate,
+}
+
+pub fn init(console: SimpleLogger, console_level: LevelFilter, file_config: Option<FileLogConfig>) -> Result<()> {
+    let console = QUIET_TARGETS.iter()
+        .fold(console, |console, target| console.with_module_level(target, LevelFilter::Warn))
+        .with_module_level(INVOCATION_TARGET, LevelFilter::Off);
+    let file = match file_config {
+        Some(file_config) => Some(FileLogger {
+            level: file_config.level,
+            file: Mutex::new(RotatingFile::open(file_config.path.clone(), file_config.rotation, file_config.keep)
+                .map_err(|err| anyhow!("Unable to open log file {:?}: {}", file_config.path, err))?),
+        }),
+        None => None,
+    };
+
+    let max_level = file.as_ref().map_or(console_level, |file| file.level.max(console_level));
+    log::set_boxed_logger(Box::new(Logger { console, file }))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+/// Parse a size such as `10485760`, `512K`, `10MB` or `1G`
+pub fn parse_size(size: &str) -> Result<u64> {
+    let size = size.trim();
+    let digits_end = size.find(|c: char| !c.is_ascii_digit()).unwrap_or(size.len());
+    let (number, unit) = size.split_at(digits_end);
+    let multiplier = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1024,
+        "M" | "MB" => 1024 * 1024,
+        "G" | "GB" => 1024 * 1024 * 1024,
+        _ => return Err(anyhow!("Invalid size {:?}, expected a number of bytes optionally followed by K, M or G", size)),
+    };
+
+    number.parse::<u64>()
+        .map(|number| number * multiplier)
+        .map_err(|_| anyhow!("Invalid size {:?}, expected a number of bytes optionally followed by K, M or G", size))
+}
+
+impl FileLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let level = match QUIET_TARGETS.iter().any(|target| metadata.target().starts_with(target)) {
+            true => self.level.min(LevelFilter::Warn),
+            false => self.level,
+        };
+        metadata.level() <= level
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.console.enabled(metadata) || self.file.as_ref().is_some_and(|file| file.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        self.console.log(record);
+
+        if let Some(file) = &self.file {
+            if file.enabled(record.metadata()) {
+                let line = format!("{} {:<5} [{}] {}\n",
+                    Local::now().to_rfc3339_opts(SecondsFormat::Millis, false),
+                    record.level(), record.target(), record.args());
+                if let Ok(mut rotating_file) = file.file.lock() {
+                    let _ = rotating_file.write_line(&line);
+                }
+            }
+        }
+    }
+
+    fn flush(&self) {
+        self.console.flush();
+        if let Some(file) = &self.file {
+            if let Ok(mut rotating_file) = file.file.lock() {
+                let _ = rotating_file.file.flush();
+            }
+        }
+    }
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, rotation: Rotation, keep: usize) -> io::Result<RotatingFile> {
+        let (size, day) = match fs::metadata(&path) {
+            Ok(metadata) => (metadata.len(), DateTime::<Local>::from(metadata.modified()?).date_naive()),
+            Err(_) => (0, Local::now().date_naive()),
+        };
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+
+        Ok(RotatingFile { path, rotation, keep, file, size, day })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let needs_rotation = match self.rotation {
+            Rotation::Never => false,
+            Rotation::Size(max_size) => self.size > 0 && self.size + line.len() as u64 > max_size,
+            Rotation::Daily => self.day != Local::now().date_naive(),
+        };
+        if needs_rotation {
+            self.rotate()?;
+        }
+
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        match self.rotation {
+            Rotation::Never => return Ok(()),
+            Rotation::Size(_) if self.keep == 0 => fs::remove_file(&self.path)?,
+            Rotation::Size(_) => {
+                for index in (1..self.keep).rev() {
+                    rename_if_exists(&self.numbered_path(index), &self.numbered_path(index + 1))?;
+                }
+                fs::rename(&self.path, self.numbered_path(1))?;
+            }
+            Rotation::Daily => {
+                fs::rename(&self.path, self.suffixed_path(&self.day.format("%Y-%m-%d").to_string()))?;
+                self.remove_old_daily_files()?;
+            }
+        }
+
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        self.day = Local::now().date_naive();
+        Ok(())
+    }
+
+    fn remove_old_daily_files(&self) -> io::Result<()> {
+        let file_name = self.path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        let prefix = format!("{}.", file_name);
+        let mut rotated_files = fs::read_dir(parent_directory(&self.path))?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix(&prefix))
+                .is_some_and(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").is_ok()))
+            .collect::<Vec<PathBuf>>();
+        rotated_files.sort();
+
+        let excess = rotated_files.len().saturating_sub(self.keep);
+        for rotated_file in &rotated_files[..excess] {
+            fs::remove_file(rotated_file)?;
+        }
+        Ok(())
+    }
+
+    fn numbered_path(&self, index: usize) -> PathBuf {
+        self.suffixed_path(&index.to_string())
+    }
+
+    fn suffixed_path(&self, suffix: &str) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".");
+        path.push(suffix);
+        PathBuf::from(path)
+    }
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
@@ -1,151 +1,832 @@
+mod cli_error;
+mod config;
+mod dry_run;
+mod jars;
+mod logging;
+mod output;
+mod tree;
+
 use anyhow::{anyhow, Result};
-use std::{fs::File, io::Read, path::Path, str::FromStr};
+use chrono::NaiveDateTime;
+use std::{cmp::Reverse, env, fs, io::{self, Read, Write}, path::Path, process::{self, ExitCode}};
 use std::ffi::OsStr;
-use clap::{Parser, AppSettings, Subcommand};
-use configparser::ini::Ini;
-use log::LevelFilter;
+use clap::{ArgEnum, Parser, AppSettings, Subcommand};
+use encoding_rs::Encoding;
+use log::{debug, info, warn, LevelFilter};
+use regex::Regex;
 use simple_logger::SimpleLogger;
-use zip::ZipArchive;
+use tempfile::Builder;
+
+use sicas_audit::archive::{CompressionMethod, EntryCompression};
+use sicas_audit::atomic::JarLock;
+use serde::Serialize;
+use sicas_audit::audit::{parse_since, parse_until, Action, AuditEntry, AuditFilter, TIMESTAMP_FORMAT};
+use sicas_audit::backup::{Backup, Backups};
+use sicas_audit::encoding::{parse_encoding, write_hexdump};
+use sicas_audit::patterns::IgnoredFiles;
+use sicas_audit::{ArchiveFile, AuditArchive, Error, PendingChange};
+
+use crate::cli_error::{CliError, ErrorFormat, EXIT_CODES_HELP};
+use crate::config::{parse_compression, parse_compression_level, CliOverrides, Config};
+use crate::dry_run::print_changes;
+use crate::logging::{FileLogConfig, INVOCATION_TARGET};
+use crate::jars::resolve_jars;
+use crate::output::{print_document, print_documents, Document, JarDocument, OutputFormat, FORMAT_HELP};
+use crate::tree::ArchiveTree;
 
-const EMPTY_STRING: &str = "";
+/// Project configuration file used when --config is not given, skipped when it does not exist
+const DEFAULT_CONFIG_FILE: &str = "config.ini";
 
 #[derive(Parser)]
-#[clap(author, version)]
+#[clap(author, version, after_help = EXIT_CODES_HELP)]
 #[clap(global_setting(AppSettings::UseLongFormatForHelpSubcommand))]
 struct Args {
-    /// Name of the jar file
+    /// JAR file, directory searched recursively for JAR, WAR and EAR files, or quoted glob
+    /// pattern such as "deploy/**/*.jar". Show, List and Verify accept it several times, the
+    /// other commands need exactly one JAR. Required by every command except Config
     #[clap(short, long)]
-    jar: String,
+    jar: Vec<String>,
 
     /// Show debug information
     #[clap(short, long)]
     verbose: bool,
 
-    /// Configuration file location
-    #[clap(short, long, default_value = "config.ini")]
-    config: String,
+    /// Project configuration file, config.ini in the current directory by default. It overrides
+    /// the system and user configuration files and is overridden by SICAS_AUDIT_* environment
+    /// variables, e.g. SICAS_AUDIT_LOG_LEVEL. Unlike the default, a file given here must exist
+    #[clap(short, long)]
+    config: Option<String>,
 
-    /// Name of the audit trail file
+    /// Name of the audit trail file, overrides AUDIT_FILE
     #[clap(short, long)]
     file: Option<String>,
 
+    /// Encoding of the audit trail file, e.g. utf-8, utf-16le or latin1. Detected from a byte
+    /// order mark or the contents when omitted
+    #[clap(short, long, parse(try_from_str = parse_encoding))]
+    encoding: Option<&'static Encoding>,
+
+    /// Configuration profile to use, a [profile.<name>] section of the configuration.
+    /// Defaults to the profile whose JAR_PATTERN matches the name of the JAR file
+    #[clap(short, long)]
+    profile: Option<String>,
+
+    /// Print what Edit, Add, Delete, Rename, Log or Restore would change without writing the JAR
+    #[clap(long, global = true)]
+    dry_run: bool,
+
+    /// Output format of Show, List and Verify
+    #[clap(long, global = true, arg_enum, default_value = "text", long_help = FORMAT_HELP)]
+    format: OutputFormat,
+
+    /// Format of the error printed when a command fails. The exit codes are listed below
+    #[clap(long, global = true, arg_enum, default_value = "text")]
+    error_format: ErrorFormat,
+
     #[clap(subcommand)]
     command: Commands,
 }
 
 #[derive(Subcommand)]
 enum Commands {
-    /// Display contents of the archived file
-    Show,
+    /// Display contents of the archived file. Filters are combined, an entry must match all of them
+    Show {
+        /// Only show entries on or after this date (YYYY-MM-DD or YYYY-MM-DD HH:MM:SS)
+        #[clap(long, parse(try_from_str = parse_since))]
+        since: Option<NaiveDateTime>,
+
+        /// Only show entries on or before this date (YYYY-MM-DD or YYYY-MM-DD HH:MM:SS)
+        #[clap(long, parse(try_from_str = parse_until))]
+        until: Option<NaiveDateTime>,
+
+        /// Only show entries made by this user
+        #[clap(long)]
+        user: Option<String>,
+
+        /// Only show entries with this action, e.g. EDIT or DELETE
+        #[clap(long)]
+        action: Option<Action>,
+
+        /// Only show entries matching this regular expression
+        #[clap(long)]
+        grep: Option<Regex>,
+
+        /// Write the exact bytes of the file to stdout, without decoding or filtering them. Only
+        /// for a single JAR
+        #[clap(long, conflicts_with_all = &["since", "until", "user", "action", "grep"])]
+        raw: bool,
+    },
     /// List all within the archive. This can be customized in the configuration file
-    List,
+    List {
+        /// Show size, compression, CRC32, modification time and permissions of every file
+        #[clap(short, long)]
+        long: bool,
+
+        /// Sort by name, by size (largest first) or by modification time (newest first).
+        /// Files are listed in archive order by default
+        #[clap(short, long, arg_enum)]
+        sort: Option<SortOrder>,
+
+        /// Show the archive as a directory tree with file counts and sizes per directory.
+        /// Only affects text output
+        #[clap(short, long, conflicts_with_all = &["long", "sort"])]
+        tree: bool,
+    },
     /// Edit a file within the archive
     Edit {
         /// Name of the file from the archive. If no file is provided, the value in the configuration file is used
         file: Option<String>
     },
+    /// Add a file from disk to the archive
+    Add {
+        /// File to add
+        source: String,
+
+        /// Path of the entry in the archive, e.g. "config/app.properties". Defaults to the file
+        /// name of the source
+        path: Option<String>,
+
+        /// Overwrite the entry when it already exists
+        #[clap(short, long)]
+        replace: bool,
+
+        /// Compression method. Defaults to COMPRESSION in the configuration
+        #[clap(long, possible_values = &["stored", "deflated"], parse(try_from_str = parse_compression))]
+        compression: Option<CompressionMethod>,
+
+        /// Compression level from 0 (fastest) to 9 (smallest). Defaults to COMPRESSION_LEVEL in
+        /// the configuration
+        #[clap(long, parse(try_from_str = parse_compression_level))]
+        level: Option<u32>,
+
+        /// Allow changing the manifest and signature files of a signed JAR under META-INF
+        #[clap(long)]
+        force: bool,
+    },
+    /// Rename a file, or move a directory with everything below it. The rename is recorded in
+    /// the audit trail
+    Rename {
+        /// Current name of the file or directory
+        from: String,
+
+        /// New name of the file or directory. A file renamed to a name ending in "/" is moved
+        /// into that directory
+        to: String,
+
+        /// User making the change. Defaults to $USER
+        #[clap(short, long)]
+        user: Option<String>,
+
+        /// Reason for the rename, added to the audit trail entry
+        #[clap(short, long)]
+        message: Option<String>,
+    },
     /// Remove a file from the archive
     Delete {
-        /// Name of the file from the archive
+        /// Name of the file from the archive. Directories are removed with everything below them
         file: String
-    }
+    },
+    /// Append an entry to the audit trail, creating it when the JAR has none yet
+    #[clap(alias = "append")]
+    Log {
+        /// File or component the entry is about
+        target: String,
+
+        /// Action that was performed, e.g. EDIT or DEPLOY
+        #[clap(short, long, default_value = "EDIT")]
+        action: Action,
+
+        /// User making the change. Defaults to $USER
+        #[clap(short, long)]
+        user: Option<String>,
+
+        /// Description of the change. Read from stdin when omitted
+        #[clap(short, long)]
+        message: Option<String>,
+    },
+    /// Extract entries to a directory, keeping their paths and modification times
+    Extract {
+        /// Names, directories or glob patterns of the entries to extract, e.g. "META-INF/*.MF" or
+        /// "**/*.properties". The whole archive is extracted when none are given
+        entries: Vec<String>,
+
+        /// Directory to extract to. It is created when missing
+        #[clap(short, long, default_value = ".")]
+        output: String,
+
+        /// Skip entries matched by IGNORED_FILES
+        #[clap(short, long)]
+        ignore: bool,
+
+        /// Replace files that already exist in the output directory
+        #[clap(long)]
+        overwrite: bool,
+    },
+    /// List the backups of the JAR, or restore one of them. The current JAR is backed up first
+    Restore {
+        /// Number of the backup as listed, 1 being the newest, or its file name
+        backup: Option<String>,
+    },
+    /// Verify the hash chain of the audit trail and report the first broken link
+    Verify,
+    /// Print the effective configuration, including defaults, and where each value comes from
+    Config,
 }
 
-fn main() -> Result<()> {
+fn main() -> ExitCode {
     let args: Args = Args::parse();
-    if !Path::new(&args.jar).exists() {
-        return Err(anyhow!("Unable to open JAR file: {:?}", args.jar));
+    let error_format = args.error_format;
+
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => err.report(error_format),
+    }
+}
+
+fn run(args: Args) -> Result<(), CliError> {
+    // The profile is chosen by the JAR name when there is a single one
+    let config = load_config(&args, match args.jar.as_slice() {
+        [jar] => Some(jar),
+        _ => None,
+    })?;
+    init_logger(&args, &config).map_err(CliError::Config)?;
+    info!(target: INVOCATION_TARGET, "{} {} run by {}: {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"),
+        env::var("USER").unwrap_or_else(|_| "unknown".to_string()), env::args().collect::<Vec<String>>().join(" "));
+    for warning in &config.warnings {
+        warn!("{}", warning);
+    }
+    if args.config.is_none() && !Path::new(DEFAULT_CONFIG_FILE).exists() {
+        debug!("No configuration found at {:?}, using built-in defaults", DEFAULT_CONFIG_FILE);
+    }
+    if let Some(profile) = &config.profile {
+        debug!("Using profile {}", profile);
+    }
+
+    if let Commands::Config = args.command {
+        print!("{}", config.to_ini_string());
+        return Ok(());
     }
 
-    let mut config = Ini::new();
-    let _ = config.load(&args.config)
-        .expect("Unable to load configuration");
-    init_simple_logger(&args, &config);
+    let jars = resolve_jars(&args.jar)?;
+    if jars.is_empty() {
+        return Err(CliError::Usage("A JAR file is required, pass it with --jar".to_string()));
+    }
+    if let Commands::Show { .. } | Commands::List { .. } | Commands::Verify = args.command {
+        return run_on_jars(&args, &config, &jars);
+    }
+
+    let jar = match jars.as_slice() {
+        [jar] => jar.clone(),
+        jars => return Err(CliError::Usage(format!("Only Show, List and Verify work on several JARs, {} were given", jars.len()))),
+    };
+    let config = match args.jar.as_slice() {
+        [value] if *value == jar => config,
+        _ => load_config(&args, Some(&jar))?,
+    };
+
+    // Restoring works on the backups alone, the JAR itself may be damaged
+    if let Commands::Restore { backup } = args.command {
+        let backups = Backups::new(&jar, config.backup.directory.as_deref(), config.backup.keep);
+        return restore_backup(&jar, &backups, backup, args.dry_run, args.format);
+    }
+
+    let archive = open_audit_archive(&args, &config, &jar)?;
+    let ignored_files = ignored_files(&config)?;
+
+    // Held until the command is done, so concurrent changes cannot overwrite each other. A dry
+    // run writes nothing and does not wait for other changes.
+    let _lock = match &args.command {
+        _ if args.dry_run => None,
+        Commands::Edit { .. } | Commands::Add { .. } | Commands::Delete { .. } | Commands::Rename { .. }
+        | Commands::Log { .. } => Some(archive.lock()?),
+        _ => None,
+    };
 
     match args.command {
-        Commands::Show => {
-            let file = args.file.unwrap_or_else(|| config.get("AUDIT", "AUDIT_FILE")
-                .unwrap_or_else(|| "AUDIT_TRAIL".to_string()));
+        Commands::Edit { file } => {
+            let file = file.unwrap_or_else(|| archive.audit_file().to_string());
+            let original_contents = archive.read(&file)?;
+            let edited_contents = open_in_editor(&file, &original_contents)?;
+            if edited_contents == original_contents {
+                info!("No changes made to {}", file);
+                return Ok(());
+            }
 
-            let audit_trail = retrieve_archive_file_contents(&args.jar, file)?;
-            println!("{}", audit_trail);
+            if commit_change(&jar, archive.edit(&file, edited_contents)?, args.dry_run)? {
+                info!("Updated {} in {}", file, jar);
+            }
         }
-        Commands::List => {
-            let ignored_str = config.get("AUDIT", "IGNORED_FILES").unwrap_or_else(|| EMPTY_STRING.to_string());
-            let ignored_files = ignored_str.split(", ").collect::<Vec<&str>>();
-            let archive_files = traverse_archive_file(&args.jar, ignored_files)?;
+        Commands::Add { source, path, replace, compression, level, force } => {
+            let path = match path {
+                Some(path) => path,
+                None => get_file_name(&source)
+                    .ok_or_else(|| CliError::Usage(format!("Unable to determine the archive path of {:?}, pass it explicitly", source)))?
+                    .to_string(),
+            };
+            let compression = EntryCompression {
+                method: compression.unwrap_or(config.archive.compression.method),
+                level: level.unwrap_or(config.archive.compression.level),
+            };
+            let contents = fs::read(&source)
+                .map_err(|err| Error::Io { context: format!("Unable to read {:?}", source), source: err })?;
 
-            println!("{:#?}", archive_files);
+            let replaced = archive.contains(&path)?;
+            let change = archive.add(&path, contents, compression, replace, force).map_err(with_hint)?;
+            if commit_change(&jar, change, args.dry_run)? {
+                info!("{} {} in {}", if replaced { "Replaced" } else { "Added" }, path, jar);
+            }
         }
-        Commands::Edit { file } => {
-            println!("Editing {:?}", file);
+        Commands::Delete { file } => {
+            let (change, removed_files) = archive.delete(&file)?;
+            if commit_change(&jar, change, args.dry_run)? {
+                for removed_file in &removed_files {
+                    debug!("Removed {}", removed_file);
+                }
+                info!("Deleted {} {} from {}", removed_files.len(),
+                    if removed_files.len() == 1 { "entry" } else { "entries" }, jar);
+            }
+        }
+        Commands::Rename { from, to, user, message } => {
+            let user = current_user(user)?;
+            let (change, renames) = archive.rename(&from, &to, |renames| {
+                // A file moved into a directory is recorded with its new name
+                let new_name = match renames {
+                    [(old_name, new_name)] if *old_name == from => new_name.as_str(),
+                    _ => to.as_str(),
+                };
+                let comment = match &message {
+                    Some(message) => format!("Renamed to {}: {}", new_name, message),
+                    None => format!("Renamed to {}", new_name),
+                };
+                AuditEntry::new(&user, Action::Rename, &from, &comment).map_err(Error::InvalidAuditEntry)
+            }).map_err(with_hint)?;
+            if commit_change(&jar, change, args.dry_run)? {
+                for (old_name, new_name) in &renames {
+                    debug!("Renamed {} to {}", old_name, new_name);
+                }
+                info!("Renamed {} to {} in {}, {} {} moved", from, to, jar, renames.len(),
+                    if renames.len() == 1 { "entry" } else { "entries" });
+            }
         }
-        Commands::Delete {file} => {
-            println!("Deleting {}", file);
+        Commands::Log { target, action, user, message } => {
+            let user = current_user(user)?;
+            let message = match message {
+                Some(message) => message,
+                None => {
+                    let mut message = String::new();
+                    io::stdin().read_to_string(&mut message)?;
+                    message
+                }
+            };
+
+            let entry = AuditEntry::new(&user, action, &target, &message)
+                .map_err(Error::InvalidAuditEntry)?;
+            let (change, entry) = archive.append_entry(entry).map_err(with_hint)?;
+            if commit_change(&jar, change, args.dry_run)? {
+                info!("Appended to {} in {}: {}", archive.audit_file(), jar, entry);
+            }
         }
+        Commands::Extract { entries, output, ignore, overwrite } => {
+            let ignored_files = if ignore { Some(&ignored_files) } else { None };
+            let output = Path::new(&output);
+            let extracted_files = archive.extract(&entries, output, ignored_files, overwrite)
+                .map_err(|err| match err {
+                    Error::Io { ref source, .. } if source.kind() == io::ErrorKind::AlreadyExists => {
+                        CliError::Archive { error: err, hint: Some("pass --overwrite to replace it") }
+                    }
+                    err => err.into(),
+                })?;
+            info!("Extracted {} {} from {} to {:?}", extracted_files,
+                if extracted_files == 1 { "file" } else { "files" }, jar, output);
+        }
+        Commands::Show { .. } | Commands::List { .. } | Commands::Verify => unreachable!("Read-only commands are run on every JAR above"),
+        Commands::Restore { .. } => unreachable!("Restore is handled before the JAR is opened"),
+        Commands::Config => unreachable!("Config is handled before the JAR is opened"),
     }
 
     Ok(())
 }
 
-fn init_simple_logger(args: &Args, config: &Ini) {
-    let logging_level = config.get("LOGGING", "LOG_LEVEL")
-        .map_or_else(|| LevelFilter::Info, |lvl| LevelFilter::from_str(lvl.as_str()).unwrap());
+fn load_config(args: &Args, jar: Option<&String>) -> Result<Config, CliError> {
+    let project_file = match &args.config {
+        Some(config) if !Path::new(config).exists() => {
+            return Err(CliError::Config(anyhow!("Configuration file {:?} does not exist", config)));
+        }
+        Some(config) => Path::new(config),
+        None => Path::new(DEFAULT_CONFIG_FILE),
+    };
 
-    let mut simple_logger = SimpleLogger::new()
-        .with_colors(true)
-        .with_level(logging_level);
+    Config::load(project_file, &CliOverrides {
+        audit_file: args.file.clone(),
+        profile: args.profile.clone(),
+        jar: jar.cloned(),
+    }).map_err(CliError::Config)
+}
+
+fn open_audit_archive(args: &Args, config: &Config, jar: &str) -> Result<AuditArchive, CliError> {
+    Ok(AuditArchive::open(jar)?
+        .with_audit_file(&config.audit.audit_file)
+        .with_encoding(args.encoding)
+        .with_backups(config.backup.directory.as_deref(), config.backup.keep))
+}
+
+fn ignored_files(config: &Config) -> Result<IgnoredFiles, CliError> {
+    IgnoredFiles::new(config.audit.ignored_files.iter().map(String::as_str))
+        .map_err(|err| CliError::Config(err.into()))
+}
+
+/// A JAR opened with its own configuration, for the commands that work on several JARs
+struct JarTarget<'a> {
+    jar: &'a str,
+    archive: AuditArchive,
+    ignored_files: IgnoredFiles,
+}
+
+/// What Show, List or Verify found in one JAR
+struct JarListing<T> {
+    /// The audit trail, for the commands reading it
+    file: Option<String>,
+    entries: Vec<T>,
+    /// A failure found along with the entries, reported once they are printed
+    problem: Option<CliError>,
+}
+
+/// Result of Verify, the single entry of its structured output
+#[derive(Serialize)]
+struct ChainReport {
+    verified: usize,
+    unchained: usize,
+    broken_line: Option<usize>,
+    broken_reason: Option<String>,
+}
 
-    if args.verbose {
-        simple_logger = simple_logger.with_level(LevelFilter::Debug);
+fn run_on_jars(args: &Args, config: &Config, jars: &[String]) -> Result<(), CliError> {
+    match &args.command {
+        Commands::Show { since, until, user, action, grep, raw } => {
+            if let (Some(since), Some(until)) = (since, until) {
+                if since > until {
+                    return Err(CliError::Usage(format!("--since {} is after --until {}", since, until)));
+                }
+            }
+
+            if *raw && jars.len() > 1 {
+                return Err(CliError::Usage(format!("--raw writes the audit trail of a single JAR, {} were given", jars.len())));
+            }
+
+            let filter = AuditFilter { since: *since, until: *until, user: user.clone(), action: action.clone(), pattern: grep.clone() };
+            // The raw bytes are written as they are, whatever the format
+            let format = if *raw { OutputFormat::Text } else { args.format };
+            for_each_jar(args, config, jars, format, |target| show_audit_trail(target, &filter, *raw, format))
+        }
+        Commands::List { long, sort, tree } => {
+            for_each_jar(args, config, jars, args.format, |target| list_archive(target, *long, *sort, *tree, args.format))
+        }
+        Commands::Verify => for_each_jar(args, config, jars, args.format, |target| verify_audit_trail(target, args.format)),
+        _ => unreachable!("only Show, List and Verify work on several JARs"),
+    }
+}
+
+/// Run a read-only command on every JAR. The command prints text output itself, under a header
+/// per JAR when there are several. Structured output is printed here, as one document for all
+/// JARs. A JAR that fails is reported and the others are still processed.
+fn for_each_jar<T, F>(args: &Args, config: &Config, jars: &[String], format: OutputFormat, mut command: F) -> Result<(), CliError>
+where
+    T: Serialize,
+    F: FnMut(&JarTarget) -> Result<JarListing<T>, CliError>,
+{
+    let mut open_and_run = |jar: &str| -> Result<JarListing<T>, CliError> {
+        // The name of every JAR may select its own profile
+        let loaded;
+        let config = match args.jar.as_slice() {
+            [value] if value == jar => config,
+            _ => {
+                loaded = load_config(args, Some(&jar.to_string()))?;
+                &loaded
+            }
+        };
+        let target = JarTarget { jar, archive: open_audit_archive(args, config, jar)?, ignored_files: ignored_files(config)? };
+        command(&target)
+    };
+
+    if let [jar] = jars {
+        let listing = open_and_run(jar)?;
+        if format != OutputFormat::Text {
+            print_document(format, &Document { jar, file: listing.file.as_deref(), entries: &listing.entries })?;
+        }
+        return listing.problem.map_or(Ok(()), Err);
+    }
+
+    let mut results = Vec::with_capacity(jars.len());
+    for (index, jar) in jars.iter().enumerate() {
+        if format == OutputFormat::Text {
+            if index > 0 {
+                println!();
+            }
+            println!("==> {} <==", jar);
+        }
+
+        let (file, entries, error) = match open_and_run(jar) {
+            Ok(listing) => (listing.file, listing.entries, listing.problem),
+            Err(err) => (None, Vec::new(), Some(err)),
+        };
+        if let Some(error) = &error {
+            error.report_for(Some(jar), args.error_format);
+        }
+        results.push((jar, file, entries, error));
+    }
+
+    if format != OutputFormat::Text {
+        let documents = results.iter()
+            .map(|(jar, file, entries, error)| JarDocument {
+                document: Document { jar, file: file.as_deref(), entries },
+                error: error.as_ref().map(|error| error.to_report(None)),
+            })
+            .collect::<Vec<JarDocument<T>>>();
+        print_documents(format, &documents)?;
     }
 
-    simple_logger
-        .init()
-        .unwrap();
+    let failures = results.iter()
+        .filter_map(|(_, _, _, error)| error.as_ref())
+        .collect::<Vec<&CliError>>();
+    match failures.first() {
+        Some(first_failure) => Err(CliError::JarsFailed { kind: first_failure.kind(), failed: failures.len(), total: jars.len() }),
+        None => Ok(()),
+    }
 }
 
-fn retrieve_archive_file_contents(jar: &str, archive_file_name: String) -> Result<String> {
-    let jar_file = File::open(jar)?;
-    let mut archive = ZipArchive::new(jar_file)?;
-    let mut archive_file = archive.by_name(archive_file_name.as_str())?;
-    let mut file_contents = String::new();
+fn show_audit_trail(target: &JarTarget, filter: &AuditFilter, raw: bool, format: OutputFormat) -> Result<JarListing<AuditEntry>, CliError> {
+    let archive = &target.archive;
+    let file = archive.audit_file();
+    let mut listing = JarListing { file: Some(file.to_string()), entries: Vec::new(), problem: None };
+
+    if raw {
+        io::stdout().lock().write_all(&archive.read_audit_file()?)?;
+        return Ok(listing);
+    }
+    let audit_trail = match archive.audit_trail() {
+        Ok(audit_trail) => audit_trail,
+        Err(Error::NotText(_)) if format == OutputFormat::Text => {
+            warn!("{} is not text, showing a hexdump. Pass --encoding to decode it anyway or --raw for the exact bytes", file);
+            write_hexdump(&mut io::stdout().lock(), &archive.read_audit_file()?)?;
+            return Ok(listing);
+        }
+        Err(err) => return Err(with_hint(err)),
+    };
+    listing.entries = audit_trail.entries.into_iter()
+        .filter(|entry| filter.matches(entry))
+        .collect();
 
-    archive_file.read_to_string(&mut file_contents)?;
-    Ok(file_contents)
+    if format == OutputFormat::Text {
+        print_audit_table(&listing.entries);
+    }
+    if !audit_trail.malformed_lines.is_empty() {
+        for malformed_line in &audit_trail.malformed_lines {
+            warn!("{}: {}", file, malformed_line);
+        }
+        listing.problem = Some(CliError::AuditTrail(format!("{} contains {} malformed line(s)", file, audit_trail.malformed_lines.len())));
+    }
+    Ok(listing)
 }
 
-fn traverse_archive_file(jar: &str, ignored_files: Vec<&str>) -> Result<Vec<String>> {
-    let jar_file = File::open(jar)?;
-    let mut archive = ZipArchive::new(jar_file)?;
-    let mut archive_files = Vec::new();
+fn list_archive(target: &JarTarget, long: bool, sort: Option<SortOrder>, tree: bool, format: OutputFormat) -> Result<JarListing<ArchiveFile>, CliError> {
+    let include_directories = tree && format == OutputFormat::Text;
+    let mut archive_files = target.archive.list()?.into_iter()
+        .filter(|archive_file| (include_directories || !archive_file.is_dir)
+            && !target.ignored_files.is_ignored(&archive_file.name, archive_file.is_dir))
+        .collect::<Vec<ArchiveFile>>();
+    match sort {
+        Some(SortOrder::Name) => archive_files.sort_by(|a, b| a.name.cmp(&b.name)),
+        Some(SortOrder::Size) => archive_files.sort_by_key(|archive_file| Reverse(archive_file.size)),
+        Some(SortOrder::Mtime) => archive_files.sort_by_key(|archive_file| Reverse(archive_file.modified)),
+        None => {}
+    }
 
-    'outer: for index in 0..archive.len() {
-        let file = archive.by_index(index)?;
-        for ignored_file in &ignored_files {
-            if file.is_dir() || ignored_file.ends_with('/') && file.name().contains(ignored_file) {
-                continue 'outer;
-            } else if file.is_file() {
-                if ignored_file.starts_with('.') {
-                    let file_extension = get_file_extension(file.name());
-                    if file_extension.eq_ignore_ascii_case(ignored_file) {
-                        continue 'outer;
-                    }
-                } else {
-                    let file_name = get_file_name(file.name())
-                        .unwrap_or(EMPTY_STRING);
+    match format {
+        OutputFormat::Text if tree => {
+            ArchiveTree::build(archive_files.iter().map(|archive_file| (archive_file.name.as_str(), archive_file.size)))
+                .print(target.jar);
+        }
+        OutputFormat::Text if long => print_long_listing(&archive_files),
+        OutputFormat::Text => archive_files.iter().for_each(|archive_file| println!("{}", archive_file.name)),
+        _ => {}
+    }
+    Ok(JarListing { file: None, entries: archive_files, problem: None })
+}
 
-                    if file_name.starts_with(ignored_file) {
-                        continue 'outer;
-                    }
-                }
+fn verify_audit_trail(target: &JarTarget, format: OutputFormat) -> Result<JarListing<ChainReport>, CliError> {
+    let file = target.archive.audit_file();
+    let verification = target.archive.audit_trail().map_err(with_hint)?.verify_chain();
+
+    if verification.unchained > 0 {
+        warn!("{} entries of {} predate the hash chain and cannot be verified", verification.unchained, file);
+    }
+    let problem = match &verification.broken_link {
+        Some(broken_link) => Some(CliError::AuditTrail(format!("Hash chain of {} is broken at {}", file, broken_link))),
+        None if verification.verified == 0 => Some(CliError::AuditTrail(format!("{} has no chained entries to verify", file))),
+        None => None,
+    };
+    if problem.is_none() && format == OutputFormat::Text {
+        println!("{}: hash chain intact, {} entries verified", file, verification.verified);
+    }
+
+    let report = ChainReport {
+        verified: verification.verified,
+        unchained: verification.unchained,
+        broken_line: verification.broken_link.as_ref().map(|broken_link| broken_link.line_number),
+        broken_reason: verification.broken_link.map(|broken_link| broken_link.reason),
+    };
+    Ok(JarListing { file: Some(file.to_string()), entries: vec![report], problem })
+}
+
+/// Add the option of the command line that resolves an error, where there is one
+fn with_hint(error: Error) -> CliError {
+    let hint = match error {
+        Error::EntryExists { .. } => "pass --replace to overwrite it",
+        Error::SignatureFile { .. } => "pass --force to change it anyway",
+        Error::NotText(_) => "pass --encoding to decode it anyway",
+        Error::BackupNotFound { .. } => "run Restore without arguments to list them",
+        error => return error.into(),
+    };
+    CliError::Archive { error, hint: Some(hint) }
+}
+
+/// Write a change to the JAR. In a dry run the change is only printed, and false is returned.
+fn commit_change(jar: &str, change: PendingChange, dry_run: bool) -> Result<bool, CliError> {
+    if dry_run {
+        print_changes(jar, change.rewrite())?;
+        return Ok(false);
+    }
+
+    if let Some(backup) = change.commit()? {
+        info!("Saved backup {:?}", backup);
+    }
+    Ok(true)
+}
+
+/// List the backups of the JAR, or restore one of them
+fn restore_backup(jar: &str, backups: &Backups, backup: Option<String>, dry_run: bool, format: OutputFormat) -> Result<(), CliError> {
+    let backup = match backup {
+        Some(backup) => backups.find(&backup).map_err(with_hint)?,
+        None => {
+            let backups_found = backups.list()?;
+            match format {
+                OutputFormat::Text if backups_found.is_empty() => println!("No backups of {} in {:?}", jar, backups.directory()),
+                OutputFormat::Text => print_backups(&backups_found),
+                format => print_document(format, &Document { jar, file: None, entries: &backups_found })?,
             }
+            return Ok(());
+        }
+    };
+
+    if dry_run {
+        println!("Dry run, {} would be restored from {:?}", jar, backup.path);
+        return Ok(());
+    }
+    let _lock = JarLock::acquire(jar)?;
+    if let Some(current_backup) = backups.restore(&backup)? {
+        info!("Saved backup {:?}", current_backup);
+    }
+    info!("Restored {} from {:?}", jar, backup.path);
+    Ok(())
+}
+
+fn init_logger(args: &Args, config: &Config) -> Result<()> {
+    let console_level = if args.verbose { LevelFilter::Debug } else { config.logging.level };
+
+    let simple_logger = SimpleLogger::new()
+        .with_colors(true)
+        .with_level(console_level);
+
+    let file_config = config.logging.file.clone().map(|path| FileLogConfig {
+        path,
+        level: config.logging.file_level,
+        rotation: config.logging.rotation,
+        keep: config.logging.keep,
+    });
+
+    logging::init(simple_logger, console_level, file_config)
+}
+
+fn print_audit_table(entries: &[AuditEntry]) {
+    const HEADERS: [&str; 5] = ["TIMESTAMP", "USER", "ACTION", "TARGET", "COMMENT"];
+
+    let rows = entries.iter()
+        .map(|entry| [
+            entry.timestamp.format(TIMESTAMP_FORMAT).to_string(),
+            entry.user.clone(),
+            entry.action.to_string(),
+            entry.target.clone(),
+            entry.comment.clone(),
+        ])
+        .collect::<Vec<[String; 5]>>();
+
+    let mut widths = HEADERS.map(str::len);
+    for row in &rows {
+        for (width, column) in widths.iter_mut().zip(row) {
+            *width = (*width).max(column.chars().count());
         }
+    }
+
+    let print_row = |row: [&str; 5]| {
+        let line = row.iter()
+            .zip(widths)
+            .map(|(column, width)| format!("{:<width$}", column, width = width))
+            .collect::<Vec<String>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    };
 
-        archive_files.push(file.name().to_owned());
+    print_row(HEADERS);
+    for row in &rows {
+        print_row(row.each_ref().map(String::as_str));
     }
+}
 
-    Ok(archive_files)
+fn print_backups(backups: &[Backup]) {
+    let size_width = backups.iter()
+        .map(|backup| backup.size.to_string().len())
+        .max()
+        .unwrap_or(0)
+        .max("SIZE".len());
+
+    println!("{:>3}  {:<19}  {:>size_width$}  PATH", "#", "CREATED", "SIZE", size_width = size_width);
+    for backup in backups {
+        println!("{:>3}  {:<19}  {:>size_width$}  {}", backup.number, backup.created.format(TIMESTAMP_FORMAT),
+            backup.size, backup.path.display(), size_width = size_width);
+    }
+}
+
+fn print_long_listing(archive_files: &[ArchiveFile]) {
+    let size_width = archive_files.iter()
+        .map(|archive_file| archive_file.size.to_string().len())
+        .max()
+        .unwrap_or(0)
+        .max("SIZE".len());
+    let compressed_width = archive_files.iter()
+        .map(|archive_file| archive_file.compressed_size.to_string().len())
+        .max()
+        .unwrap_or(0)
+        .max("COMPRESSED".len());
+
+    println!("{:<5}  {:>size_width$}  {:>compressed_width$}  {:<8}  {:<8}  {:<19}  NAME",
+        "MODE", "SIZE", "COMPRESSED", "METHOD", "CRC32", "MODIFIED",
+        size_width = size_width, compressed_width = compressed_width);
+    for archive_file in archive_files {
+        let modified = archive_file.modified
+            .map_or_else(|| "-".to_string(), |modified| modified.format(TIMESTAMP_FORMAT).to_string());
+        println!("{:<5}  {:>size_width$}  {:>compressed_width$}  {:<8}  {:<8}  {:<19}  {}",
+            archive_file.permissions.as_deref().unwrap_or("-"), archive_file.size, archive_file.compressed_size,
+            archive_file.compression, archive_file.crc32, modified, archive_file.name,
+            size_width = size_width, compressed_width = compressed_width);
+    }
+
+    let total_size = archive_files.iter().map(|archive_file| archive_file.size).sum::<u64>();
+    let total_compressed_size = archive_files.iter().map(|archive_file| archive_file.compressed_size).sum::<u64>();
+    let ratio = if total_size == 0 { 0.0 } else { 100.0 * (1.0 - total_compressed_size as f64 / total_size as f64) };
+    println!("{} files, {} bytes, {} bytes compressed ({:.1}% saved)",
+        archive_files.len(), total_size, total_compressed_size, ratio);
+}
+
+/// The user given on the command line, or else the one logged in
+fn current_user(user: Option<String>) -> Result<String, CliError> {
+    user.or_else(|| env::var("USER").ok())
+        .or_else(|| env::var("USERNAME").ok())
+        .ok_or_else(|| CliError::Usage("Unable to determine the user, set $USER or pass --user".to_string()))
+}
+
+fn open_in_editor(archive_file_name: &str, contents: &[u8]) -> Result<Vec<u8>> {
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    let mut editor_args = editor.split_whitespace();
+    let editor_program = editor_args.next()
+        .ok_or_else(|| anyhow!("No editor configured, set $VISUAL or $EDITOR"))?;
+
+    let file_name = get_file_name(archive_file_name).unwrap_or(archive_file_name);
+    let temp_file = Builder::new()
+        .prefix("sicas_audit-")
+        .suffix(&format!("-{}", file_name))
+        .tempfile()?;
+    fs::write(temp_file.path(), contents)?;
+
+    debug!("Opening {:?} with {}", temp_file.path(), editor);
+    let status = process::Command::new(editor_program)
+        .args(editor_args)
+        .arg(temp_file.path())
+        .status()
+        .map_err(|err| anyhow!("Unable to launch editor {:?}: {}", editor, err))?;
+    if !status.success() {
+        return Err(anyhow!("Editor {:?} exited with {}, archive left unchanged", editor, status));
+    }
+
+    Ok(fs::read(temp_file.path())?)
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum SortOrder {
+    Name,
+    Size,
+    Mtime,
 }
 
 fn get_file_name(file_path: &str) -> Option<&str> {
@@ -153,11 +834,3 @@ fn get_file_name(file_path: &str) -> Option<&str> {
         .file_name()
         .and_then(OsStr::to_str)
 }
-
-fn get_file_extension(file_path: &str) -> &str {
-    file_path
-        .rfind('.')
-        .map(|idx| &file_path[idx..])
-        .filter(|ext| ext.chars().skip(1).all(|c| c.is_ascii_alphanumeric()))
-        .unwrap_or(EMPTY_STRING)
-}
\ No newline at end of file
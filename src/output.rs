@@ -0,0 +1,160 @@
+use anyhow::Result;
+use clap::ArgEnum;
+use serde::Serialize;
+use std::io::{self, Write};
+
+use crate::cli_error::ErrorReport;
+
+/// Help text for `--format`, documenting the schema of the structured formats
+pub const FORMAT_HELP: &str = "Output format of Show, List and Verify
+
+json and yaml print a single document:
+    {\"jar\": \"app.jar\", \"file\": \"AUDIT_TRAIL\", \"entries\": [...]}
+`file` is only present for Show and Verify. ndjson prints one entry object per line
+and csv prints a header row followed by one row per entry.
+
+With several JARs, json and yaml print one document for all of them, where JARs that
+failed have an error and no entries:
+    {\"jars\": [{\"jar\": \"a.jar\", ...}, {\"jar\": \"b.jar\", \"entries\": [],
+               \"error\": {\"error\": \"jar_corrupt\", \"exit_code\": 5, ...}}]}
+ndjson and csv add the JAR as the first field of every entry.
+
+Show entries:
+    {\"line\": 3, \"timestamp\": \"2022-01-31T14:05:00\", \"user\": \"goodwir\",
+     \"action\": \"EDIT\", \"target\": \"config.properties\", \"comment\": \"...\",
+     \"hash\": \"<sha256 hex>\" or null}
+
+List entries:
+    {\"name\": \"META-INF/MANIFEST.MF\", \"size\": 1024, \"compressed_size\": 512,
+     \"compression\": \"Deflated\", \"crc32\": \"1c291ca3\",
+     \"modified\": \"2022-01-31T14:05:00\" or null, \"permissions\": \"0644\" or null}
+
+Verify has a single entry:
+    {\"verified\": 12, \"unchained\": 3, \"broken_line\": 17 or null,
+     \"broken_reason\": \"...\" or null}";
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+    Ndjson,
+    Csv,
+    Yaml,
+}
+
+/// Top level object of the json and yaml formats
+#[derive(Serialize)]
+pub struct Document<'a, T: Serialize> {
+    pub jar: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<&'a str>,
+    pub entries: &'a [T],
+}
+
+/// Print a document in one of the structured formats. `OutputFormat::Text` is rendered by
+/// the caller, since every command lays out its text differently.
+pub fn print_document<T: Serialize>(format: OutputFormat, document: &Document<T>) -> Result<()> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+
+    match format {
+        OutputFormat::Text | OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut stdout, document)?;
+            writeln!(stdout)?;
+        }
+        OutputFormat::Ndjson => {
+            for entry in document.entries {
+                serde_json::to_writer(&mut stdout, entry)?;
+                writeln!(stdout)?;
+            }
+        }
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(stdout);
+            for entry in document.entries {
+                writer.serialize(entry)?;
+            }
+            writer.flush()?;
+        }
+        OutputFormat::Yaml => {
+            serde_yaml::to_writer(&mut stdout, document)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// A document of one of several JARs
+#[derive(Serialize)]
+pub struct JarDocument<'a, T: Serialize> {
+    #[serde(flatten)]
+    pub document: Document<'a, T>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorReport<'a>>,
+}
+
+/// Top level object of the json and yaml formats for several JARs
+#[derive(Serialize)]
+struct Documents<'a, 'b, T: Serialize> {
+    jars: &'b [JarDocument<'a, T>],
+}
+
+/// An entry of the ndjson and csv formats for several JARs
+#[derive(Serialize)]
+struct JarEntry<'a, T: Serialize> {
+    jar: &'a str,
+    #[serde(flatten)]
+    entry: &'a T,
+}
+
+/// Print the documents of several JARs in one of the structured formats
+pub fn print_documents<T: Serialize>(format: OutputFormat, documents: &[JarDocument<T>]) -> Result<()> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let entries = documents.iter()
+        .flat_map(|document| document.document.entries.iter().map(|entry| JarEntry { jar: document.document.jar, entry }));
+
+    match format {
+        OutputFormat::Text | OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut stdout, &Documents { jars: documents })?;
+            writeln!(stdout)?;
+        }
+        OutputFormat::Ndjson => {
+            for entry in entries {
+                serde_json::to_writer(&mut stdout, &entry)?;
+                writeln!(stdout)?;
+            }
+        }
+        OutputFormat::Csv => {
+            // The csv crate cannot flatten structs, so the JAR column is prepended to the
+            // header and records of the entries themselves
+            let mut writer = csv::Writer::from_writer(stdout);
+            let mut header_written = false;
+            for entry in entries {
+                let [header, record] = csv_rows(entry.entry)?;
+                if !header_written {
+                    writer.write_record(std::iter::once("jar").chain(header.iter()))?;
+                    header_written = true;
+                }
+                writer.write_record(std::iter::once(entry.jar).chain(record.iter()))?;
+            }
+            writer.flush()?;
+        }
+        OutputFormat::Yaml => {
+            serde_yaml::to_writer(&mut stdout, &Documents { jars: documents })?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The header and the record the csv crate writes for an entry
+fn csv_rows<T: Serialize>(entry: &T) -> Result<[csv::StringRecord; 2]> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.serialize(entry)?;
+    let rows = writer.into_inner().map_err(|err| err.into_error())?;
+
+    let mut reader = csv::ReaderBuilder::new().has_headers(false).from_reader(rows.as_slice());
+    let mut records = reader.records();
+    let mut next_record = || records.next().transpose()?.ok_or_else(|| anyhow::anyhow!("CSV record missing"));
+    Ok([next_record()?, next_record()?])
+}
@@ -0,0 +1,110 @@
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use log::warn;
+
+use crate::{Error, Result};
+
+/// Archive entries excluded from `List`, described with gitignore patterns:
+/// `**/*.class`, `kotlin/`, `META-INF/*.SF`, or `!keep.dat` to re-include an entry
+pub struct IgnoredFiles {
+    matcher: Gitignore,
+}
+
+impl IgnoredFiles {
+    /// Build the matcher from the patterns of `IGNORED_FILES`, see [`parse_pattern_list`]
+    pub fn new<'a, I>(patterns: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut builder = GitignoreBuilder::new("");
+        let mut has_ignore_pattern = false;
+        for pattern in patterns {
+            if let Some(reason) = never_matching_reason(pattern, has_ignore_pattern) {
+                warn!("IGNORED_FILES pattern {:?} will not match any entry: {}", pattern, reason);
+            } else if let Some(file_name) = bare_extension(pattern) {
+                warn!("IGNORED_FILES pattern {:?} matches only files named {:?}, use \"*{}\" for the extension", pattern, file_name, file_name);
+            }
+            has_ignore_pattern |= !pattern.starts_with('!');
+
+            builder.add_line(None, pattern)
+                .map_err(|err| Error::InvalidPattern { pattern: pattern.to_string(), reason: err.to_string() })?;
+        }
+
+        let matcher = builder.build()
+            .map_err(|err| Error::InvalidPattern { pattern: String::new(), reason: err.to_string() })?;
+        Ok(IgnoredFiles { matcher })
+    }
+
+    /// Whether an archive entry, or a directory containing it, is ignored
+    pub fn is_ignored(&self, archive_path: &str, is_dir: bool) -> bool {
+        self.matcher
+            .matched_path_or_any_parents(archive_path.trim_end_matches('/'), is_dir)
+            .is_ignore()
+    }
+}
+
+/// Patterns of a list, with the problems that were skipped while parsing it
+#[derive(Debug, Default)]
+pub struct PatternList {
+    pub patterns: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+/// Split a list of patterns on commas and any whitespace, including the line breaks of a
+/// multi-line INI value. Patterns containing either can be wrapped in single or double quotes.
+pub fn parse_pattern_list(list: &str) -> Result<PatternList> {
+    let mut patterns = PatternList::default();
+    let mut pattern = String::new();
+    let mut quote: Option<char> = None;
+    let mut quoted = false;
+
+    for c in list.chars() {
+        match quote {
+            Some(open_quote) if c == open_quote => quote = None,
+            Some(_) => pattern.push(c),
+            None if c == '"' || c == '\'' => {
+                quote = Some(c);
+                quoted = true;
+            }
+            None if c == ',' || c.is_whitespace() => {
+                push_pattern(&mut patterns, &mut pattern, &mut quoted);
+            }
+            None => pattern.push(c),
+        }
+    }
+
+    if let Some(open_quote) = quote {
+        return Err(Error::InvalidPattern { pattern: list.trim().to_string(), reason: format!("unterminated {}", open_quote) });
+    }
+    push_pattern(&mut patterns, &mut pattern, &mut quoted);
+    Ok(patterns)
+}
+
+fn push_pattern(patterns: &mut PatternList, pattern: &mut String, quoted: &mut bool) {
+    if pattern.is_empty() && *quoted {
+        patterns.warnings.push("skipping empty pattern".to_string());
+    } else if !pattern.is_empty() {
+        patterns.patterns.push(std::mem::take(pattern));
+    }
+    *quoted = false;
+}
+
+fn never_matching_reason(pattern: &str, has_ignore_pattern: bool) -> Option<String> {
+    let path = pattern.strip_prefix('!').unwrap_or(pattern);
+
+    if pattern.starts_with('!') && !has_ignore_pattern {
+        Some("it re-includes entries, but no pattern before it ignores any".to_string())
+    } else if path.contains('\\') {
+        Some("archive paths are separated by '/', not '\\'".to_string())
+    } else if path.split('/').any(|component| component == "." || component == "..") {
+        Some("archive paths never contain '.' or '..' components".to_string())
+    } else {
+        None
+    }
+}
+
+/// The file name of a pattern like `.dat`, which looks like an extension but matches a whole
+/// file name
+fn bare_extension(pattern: &str) -> Option<&str> {
+    let path = pattern.strip_prefix('!').unwrap_or(pattern);
+    (path.starts_with('.') && !path[1..].contains(['.', '/', '*', '?', '['])).then_some(path)
+}
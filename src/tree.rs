@@ -0,0 +1,76 @@
+use std::collections::BTreeMap;
+
+/// Directory hierarchy of an archive with file counts and sizes aggregated per directory
+#[derive(Default)]
+pub struct ArchiveTree {
+    directories: BTreeMap<String, ArchiveTree>,
+    files: BTreeMap<String, u64>,
+    file_count: usize,
+    size: u64,
+}
+
+impl ArchiveTree {
+    /// Build the tree from archive paths and sizes. Paths ending in `/` are directory entries.
+    pub fn build<'a, I>(entries: I) -> ArchiveTree
+    where
+        I: IntoIterator<Item = (&'a str, u64)>,
+    {
+        let mut root = ArchiveTree::default();
+        for (path, size) in entries {
+            root.insert(path, size);
+        }
+        root
+    }
+
+    fn insert(&mut self, path: &str, size: u64) {
+        let mut components = path.split('/')
+            .filter(|component| !component.is_empty())
+            .collect::<Vec<&str>>();
+        let file_name = if path.ends_with('/') { None } else { components.pop() };
+
+        let mut node = self;
+        for component in std::iter::once(None).chain(components.into_iter().map(Some)) {
+            if let Some(component) = component {
+                node = node.directories.entry(component.to_string()).or_default();
+            }
+            if file_name.is_some() {
+                node.file_count += 1;
+                node.size += size;
+            }
+        }
+
+        if let Some(file_name) = file_name {
+            node.files.insert(file_name.to_string(), size);
+        }
+    }
+
+    pub fn print(&self, label: &str) {
+        println!("{} ({})", label, self.summary());
+        self.print_children("");
+    }
+
+    fn print_children(&self, prefix: &str) {
+        let child_count = self.directories.len() + self.files.len();
+        let directories = self.directories.iter()
+            .map(|(name, directory)| (format!("{}/ ({})", name, directory.summary()), Some(directory)));
+        let files = self.files.iter()
+            .map(|(name, size)| (format!("{} ({} bytes)", name, size), None));
+
+        for (index, (label, directory)) in directories.chain(files).enumerate() {
+            let (branch, indent) = if index + 1 == child_count {
+                ("└── ", "    ")
+            } else {
+                ("├── ", "│   ")
+            };
+
+            println!("{}{}{}", prefix, branch, label);
+            if let Some(directory) = directory {
+                directory.print_children(&format!("{}{}", prefix, indent));
+            }
+        }
+    }
+
+    fn summary(&self) -> String {
+        format!("{} {}, {} bytes", self.file_count, if self.file_count == 1 { "file" } else { "files" }, self.size)
+    }
+}